@@ -3,6 +3,15 @@ use std::collections::HashMap;
 use reqwest::{Client, Response};
 use serde_json::Value;
 
+use http::{build_client, TimeoutConfig, TlsConfig};
+use rest::structures::AmazonMetadataType;
+
+/// The metadata service is local to the instance, so anything slower than this means it's not there
+const METADATA_TIMEOUT: TimeoutConfig = TimeoutConfig {
+    connect: 1_000,
+    read: 2_000,
+};
+
 #[derive(Debug, Clone)]
 pub struct AwsMetadata {
     client: Client,
@@ -12,7 +21,8 @@ pub struct AwsMetadata {
 impl AwsMetadata {
     pub fn new(config: &HashMap<String, Value>) -> Self {
         AwsMetadata {
-            client: Client::new(),
+            client: build_client(METADATA_TIMEOUT, &TlsConfig::default())
+                .expect("Failed to build HTTP client"),
             host: config
                 .get("host")
                 .map(|host| host.as_str().unwrap().to_string())
@@ -20,9 +30,37 @@ impl AwsMetadata {
         }
     }
 
+    /// Metadata for this instance, with any keys that could not be looked up left empty
+    pub fn amazon_metadata(&self) -> AmazonMetadataType {
+        let mut metadata = self.fetch_metadata();
+        let mut take = |key| metadata.remove(key).unwrap_or_default();
+        AmazonMetadataType {
+            ami_launch_index: take("ami-launch-index"),
+            local_hostname: take("local-hostname"),
+            availability_zone: take("availability-zone"),
+            instance_id: take("instance-id"),
+            public_ipv4: take("public-ipv4"),
+            public_hostname: take("public-hostname"),
+            ami_manifest_path: take("ami-manifest-path"),
+            local_ipv4: take("local-ipv4"),
+            hostname: take("hostname"),
+            ami_id: take("ami-id"),
+            instance_type: take("instance-type"),
+        }
+    }
+
     fn fetch_metadata(&self) -> HashMap<&'static str, String> {
-        let mut results = HashMap::with_capacity(11);
+        let mut results = HashMap::with_capacity(14);
         results.insert("ami-id", self.lookup_metadata_key("ami-id"));
+        results.insert(
+            "ami-launch-index",
+            self.lookup_metadata_key("ami-launch-index"),
+        );
+        results.insert(
+            "ami-manifest-path",
+            self.lookup_metadata_key("ami-manifest-path"),
+        );
+        results.insert("hostname", self.lookup_metadata_key("hostname"));
         results.insert("instance-id", self.lookup_metadata_key("instance-id"));
         results.insert("instance-type", self.lookup_metadata_key("instance-type"));
         results.insert("local-ipv4", self.lookup_metadata_key("local-ipv4"));
@@ -42,11 +80,12 @@ impl AwsMetadata {
             self.lookup_instance_identity()
                 .and_then(|i| i["accountId"].as_str().map(|id| id.to_owned())),
         );
-        let mac = results["mac"].clone().unwrap();
-        results.insert(
-            "vpc-id",
-            self.lookup_metadata_key(&format!("network/interfaces/macs/{}/vpc-id", mac)),
-        );
+        if let Some(mac) = results["mac"].clone() {
+            results.insert(
+                "vpc-id",
+                self.lookup_metadata_key(&format!("network/interfaces/macs/{}/vpc-id", mac)),
+            );
+        }
         debug!("Found Instance AWS Metadata: {:?}", results);
         results
             .into_iter()
@@ -67,7 +106,8 @@ impl AwsMetadata {
             .map_err(|e| {
                 error!("Error requesting metadata key: {}", e);
                 e
-            }).ok()?;
+            })
+            .ok()?;
         response.text().ok()
     }
 
@@ -77,12 +117,14 @@ impl AwsMetadata {
             .get(&format!(
                 "http://{}/latest/dynamic/instance-identity/document",
                 self.host
-            )).send()
+            ))
+            .send()
             .and_then(Response::error_for_status)
             .map_err(|e| {
                 error!("Error requesting instance identity document: {}", e);
                 e
-            }).ok()?;
+            })
+            .ok()?;
         response.json().ok()
     }
 }
@@ -0,0 +1,368 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Configures the per-instance circuit breakers used by `EurekaClient::make_request`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    /// Consecutive failures after which an instance is ejected
+    pub consecutive_failures: usize,
+    /// Fraction of failed requests within `error_rate_window` after which an instance is ejected
+    pub error_rate_threshold: f64,
+    /// Length of the window used for the error rate, in milliseconds
+    pub error_rate_window: usize,
+    /// Requests needed within the window before the error rate is considered
+    pub min_requests_in_window: usize,
+    /// How long an instance stays ejected before a probe request is let through, in milliseconds
+    pub open_duration: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 5,
+            error_rate_threshold: 0.5,
+            error_rate_window: 10_000,
+            min_requests_in_window: 10,
+            open_duration: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    /// Requests flow normally
+    Closed,
+    /// The instance is ejected and receives no requests
+    Open,
+    /// A single probe request is allowed, to see whether the instance has recovered
+    HalfOpen,
+}
+
+/// A point-in-time view of one instance's circuit breaker, for metrics
+#[derive(Debug, Clone)]
+pub struct BreakerSnapshot {
+    pub state: BreakerState,
+    pub consecutive_failures: usize,
+    pub requests_in_window: usize,
+    pub failures_in_window: usize,
+    /// When the breaker last opened, if it is not closed
+    pub opened_at: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: usize,
+    /// Outcome of each recent request, `true` meaning it failed
+    window: VecDeque<(Instant, bool)>,
+    opened_at: Option<(Instant, SystemTime)>,
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    fn new() -> Self {
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            window: VecDeque::new(),
+            opened_at: None,
+            probe_started: None,
+        }
+    }
+
+    /// Whether `try_acquire` would let a request through, without claiming a probe
+    fn is_available(&self, config: &CircuitBreakerConfig) -> bool {
+        let open_duration = Duration::from_millis(config.open_duration as u64);
+        let since = match self.state {
+            BreakerState::Closed => return true,
+            BreakerState::Open => self.opened_at.map(|(at, _)| at),
+            BreakerState::HalfOpen => self.probe_started,
+        };
+        since
+            .map(|at| at.elapsed() >= open_duration)
+            .unwrap_or(true)
+    }
+
+    fn try_acquire(&mut self, config: &CircuitBreakerConfig) -> bool {
+        let open_duration = Duration::from_millis(config.open_duration as u64);
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => {
+                let elapsed = self.opened_at.map(|(at, _)| at.elapsed());
+                if elapsed
+                    .map(|elapsed| elapsed >= open_duration)
+                    .unwrap_or(true)
+                {
+                    self.state = BreakerState::HalfOpen;
+                    self.probe_started = Some(Instant::now());
+                    true
+                } else {
+                    false
+                }
+            }
+            BreakerState::HalfOpen => {
+                // Let another probe through if the last one never reported back
+                let probe_lost = self
+                    .probe_started
+                    .map(|at| at.elapsed() >= open_duration)
+                    .unwrap_or(true);
+                if probe_lost {
+                    self.probe_started = Some(Instant::now());
+                }
+                probe_lost
+            }
+        }
+    }
+
+    fn record(&mut self, config: &CircuitBreakerConfig, failed: bool) {
+        let now = Instant::now();
+        self.window.push_back((now, failed));
+        self.prune(config, now);
+
+        if !failed {
+            self.consecutive_failures = 0;
+            if self.state != BreakerState::Closed {
+                self.close();
+            }
+            return;
+        }
+
+        self.consecutive_failures += 1;
+        let requests = self.window.len();
+        let failures = self.window.iter().filter(|&&(_, failed)| failed).count();
+        let error_rate_exceeded = requests >= config.min_requests_in_window
+            && failures as f64 / requests as f64 >= config.error_rate_threshold;
+        if self.state == BreakerState::HalfOpen
+            || self.consecutive_failures >= config.consecutive_failures
+            || error_rate_exceeded
+        {
+            self.state = BreakerState::Open;
+            self.opened_at = Some((now, SystemTime::now()));
+            self.probe_started = None;
+        }
+    }
+
+    fn close(&mut self) {
+        self.state = BreakerState::Closed;
+        self.window.clear();
+        self.opened_at = None;
+        self.probe_started = None;
+    }
+
+    fn prune(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        let window = Duration::from_millis(config.error_rate_window as u64);
+        while let Some(&(at, _)) = self.window.front() {
+            if now.duration_since(at) <= window {
+                break;
+            }
+            self.window.pop_front();
+        }
+    }
+
+    fn snapshot(&self) -> BreakerSnapshot {
+        BreakerSnapshot {
+            state: self.state,
+            consecutive_failures: self.consecutive_failures,
+            requests_in_window: self.window.len(),
+            failures_in_window: self.window.iter().filter(|&&(_, failed)| failed).count(),
+            opened_at: self.opened_at.map(|(_, at)| at),
+        }
+    }
+}
+
+/// Circuit breakers for every instance we have sent requests to, keyed by instance ID
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    breakers: Mutex<HashMap<String, CircuitBreaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreakers {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request could be sent to the instance, without claiming the probe of an ejected one
+    pub fn is_available(&self, instance_id: &str) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let breakers = self.breakers.lock().unwrap();
+        match breakers.get(instance_id) {
+            Some(breaker) => breaker.is_available(&self.config),
+            None => true,
+        }
+    }
+
+    /// Whether a request may be sent to the instance.
+    /// For an ejected instance this lets through a single probe once its open duration has passed.
+    pub fn try_acquire(&self, instance_id: &str) -> bool {
+        if !self.config.enabled {
+            return true;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        match breakers.get_mut(instance_id) {
+            Some(breaker) => breaker.try_acquire(&self.config),
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self, instance_id: &str) {
+        self.record(instance_id, false);
+    }
+
+    pub fn record_failure(&self, instance_id: &str) {
+        self.record(instance_id, true);
+    }
+
+    fn record(&self, instance_id: &str, failed: bool) {
+        if !self.config.enabled {
+            return;
+        }
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers
+            .entry(instance_id.to_string())
+            .or_insert_with(CircuitBreaker::new);
+        let was_open = breaker.state != BreakerState::Closed;
+        breaker.record(&self.config, failed);
+        match (was_open, breaker.state) {
+            (false, BreakerState::Open) => {
+                warn!("Ejecting instance {} after repeated failures", instance_id)
+            }
+            (true, BreakerState::Closed) => info!("Instance {} has recovered", instance_id),
+            _ => {}
+        }
+    }
+
+    /// Forgets the instance's failures, as when it has restarted
+    pub fn reset(&self, instance_id: &str) {
+        if self.breakers.lock().unwrap().remove(instance_id).is_some() {
+            debug!("Reset circuit breaker for instance {}", instance_id);
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, BreakerSnapshot> {
+        self.breakers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, breaker)| (id.clone(), breaker.snapshot()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            consecutive_failures: 3,
+            min_requests_in_window: 100,
+            ..CircuitBreakerConfig::default()
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let config = config();
+        let mut breaker = CircuitBreaker::new();
+        breaker.record(&config, true);
+        breaker.record(&config, true);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record(&config, true);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.is_available(&config));
+        assert!(!breaker.try_acquire(&config));
+    }
+
+    #[test]
+    fn success_resets_consecutive_failures() {
+        let config = config();
+        let mut breaker = CircuitBreaker::new();
+        breaker.record(&config, true);
+        breaker.record(&config, true);
+        breaker.record(&config, false);
+        breaker.record(&config, true);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert_eq!(breaker.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn opens_on_error_rate() {
+        let config = CircuitBreakerConfig {
+            consecutive_failures: 100,
+            min_requests_in_window: 4,
+            error_rate_threshold: 0.5,
+            ..CircuitBreakerConfig::default()
+        };
+        let mut breaker = CircuitBreaker::new();
+        for &failed in &[true, false, true] {
+            breaker.record(&config, failed);
+        }
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record(&config, true);
+        assert_eq!(breaker.state, BreakerState::Open);
+    }
+
+    #[test]
+    fn half_open_lets_one_probe_through() {
+        let config = CircuitBreakerConfig {
+            open_duration: 0,
+            ..config()
+        };
+        let mut breaker = CircuitBreaker::new();
+        for _ in 0..3 {
+            breaker.record(&config, true);
+        }
+        assert!(breaker.is_available(&config));
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(breaker.try_acquire(&config));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+
+        // A failed probe ejects the instance again
+        breaker.record(&config, true);
+        assert_eq!(breaker.state, BreakerState::Open);
+
+        // A successful one closes the breaker
+        assert!(breaker.try_acquire(&config));
+        breaker.record(&config, false);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(breaker.window.is_empty());
+    }
+
+    #[test]
+    fn half_open_rejects_while_probe_is_in_flight() {
+        let mut config = CircuitBreakerConfig {
+            open_duration: 0,
+            ..config()
+        };
+        let mut breaker = CircuitBreaker::new();
+        for _ in 0..3 {
+            breaker.record(&config, true);
+        }
+        assert!(breaker.try_acquire(&config));
+        config.open_duration = 60_000;
+        assert!(!breaker.is_available(&config));
+        assert!(!breaker.try_acquire(&config));
+    }
+
+    #[test]
+    fn reset_forgets_failures() {
+        let breakers = CircuitBreakers::new(config());
+        for _ in 0..3 {
+            breakers.record_failure("a");
+        }
+        assert!(!breakers.is_available("a"));
+        breakers.reset("a");
+        assert!(breakers.is_available("a"));
+        assert!(breakers.snapshot().is_empty());
+    }
+}
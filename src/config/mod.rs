@@ -0,0 +1,551 @@
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+mod spring;
+
+use builder::{apply_service_url, invalid};
+use {BaseConfig, EurekaConfig, EurekaError, Instance};
+
+/// Loads a `BaseConfig` from config files, environment variables and overrides,
+/// using the same layout as Spring Cloud Netflix:
+///
+/// ```yaml
+/// eureka:
+///   client:
+///     serviceUrl:
+///       defaultZone: http://localhost:8761/eureka
+///     registryFetchInterval: 30000
+///   instance:
+///     app: MY-SERVICE
+///     port: 8080
+/// ```
+///
+/// `eureka.client` holds the fields of `EurekaConfig` and `eureka.instance` those of `Instance`.
+/// Keys may be written in camelCase, kebab-case or as dotted paths.
+/// Spring Cloud Netflix properties are also understood, such as `eureka.client.registryFetchIntervalSeconds`,
+/// `eureka.instance.metadataMap.*`, `spring.application.name` and `server.port`,
+/// so that config files can be shared with Spring services.
+/// Environment variables such as `EUREKA_CLIENT_SERVICEURL_DEFAULTZONE` map onto the same keys.
+///
+/// Each layer overrides the ones added before it, so add them from lowest to highest precedence.
+/// YAML files need the `yaml` feature, and TOML files the `toml` feature.
+#[derive(Debug)]
+pub struct ConfigLoader {
+    /// The default config, used to resolve key names and the types of string values
+    schema: Value,
+    /// The same for the Spring properties we translate
+    spring: Value,
+    /// Settings from every layer added so far
+    tree: Value,
+    /// The first error from adding a layer, reported by `load`
+    error: Option<EurekaError>,
+}
+
+impl Default for ConfigLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        let defaults = BaseConfig::default();
+        let mut client = serde_json::to_value(&defaults.eureka).unwrap_or_default();
+        client["serviceUrl"] = json_object(vec![("defaultZone", Value::Null)]);
+        let mut instance = serde_json::to_value(&defaults.instance).unwrap_or_default();
+        // Skipped when serializing the defaults, but still valid keys
+        instance["instanceId"] = Value::Null;
+        let schema = json_object(vec![(
+            "eureka",
+            json_object(vec![("client", client), ("instance", instance)]),
+        )]);
+        ConfigLoader {
+            schema,
+            spring: spring::schema(),
+            tree: Value::Object(Map::new()),
+            error: None,
+        }
+    }
+
+    /// Adds a config file, in YAML, TOML or JSON depending on its extension
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        let path = path.as_ref();
+        match read_file(path) {
+            Ok(values) => self.merge_values(values),
+            Err(e) => self.fail(e),
+        }
+        self
+    }
+
+    /// Same as `file`, but does nothing if the file does not exist
+    pub fn optional_file<P: AsRef<Path>>(self, path: P) -> Self {
+        if path.as_ref().exists() {
+            self.file(path)
+        } else {
+            self
+        }
+    }
+
+    /// Adds the `EUREKA_*` environment variables
+    pub fn env(mut self) -> Self {
+        for (name, value) in env::vars() {
+            if name.starts_with("EUREKA_") {
+                let path = name.to_lowercase();
+                self.insert_str(path.split('_').collect(), &value);
+            }
+        }
+        self
+    }
+
+    /// Overrides a single setting, such as `eureka.client.fetchRegistry`.
+    ///
+    /// Like environment variables, `value` is converted to the type of the setting,
+    /// and comma-separated for lists.
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        self.insert_str(key.split('.').collect(), value);
+        self
+    }
+
+    /// Fails with an error naming the offending key if any setting is invalid
+    pub fn load(mut self) -> Result<BaseConfig, EurekaError> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        let mut client = self.section("client");
+        let mut instance = self.section("instance");
+        let default_zone = client
+            .get("serviceUrl")
+            .and_then(|service_url| service_url.get("defaultZone"))
+            .and_then(Value::as_str)
+            .map(|zones| zones.to_string());
+        if let Some(client) = client.as_object_mut() {
+            client.remove("serviceUrl");
+        }
+        if let Some(instance) = instance.as_object_mut() {
+            normalize_instance(instance);
+        }
+
+        let mut eureka: EurekaConfig = self.deserialize_section("client", client)?;
+        if let Some(zones) = default_zone {
+            // Eureka servers replicate to each other, so any of them will do
+            let mut zones = zones.split(',').map(str::trim);
+            let first = zones.next().unwrap_or_default();
+            if zones.next().is_some() {
+                warn!(
+                    "Several Eureka servers are configured, only {} is used",
+                    first
+                );
+            }
+            apply_service_url(&mut eureka, "eureka.client.serviceUrl.defaultZone", first)?;
+        }
+        let instance: Instance = self.deserialize_section("instance", instance)?;
+        Ok(BaseConfig { eureka, instance })
+    }
+
+    fn fail(&mut self, error: EurekaError) {
+        self.error.get_or_insert(error);
+    }
+
+    fn merge_values(&mut self, values: Value) {
+        let values = match values {
+            Value::Object(values) => values,
+            Value::Null => return,
+            _ => {
+                self.fail(invalid(
+                    "eureka",
+                    "config files must contain a map of settings".to_string(),
+                ));
+                return;
+            }
+        };
+        for (key, value) in values {
+            self.insert(key.split('.').collect(), value);
+        }
+    }
+
+    /// Sets the value at `path`, converting the string to the type of the default value there
+    fn insert_str(&mut self, path: Vec<&str>, value: &str) {
+        let path = self.resolve(&path);
+        let value = coerce(value, self.schema_at(&path));
+        self.insert(path.iter().map(String::as_str).collect(), value);
+    }
+
+    fn insert(&mut self, path: Vec<&str>, value: Value) {
+        let path = self.resolve(&path);
+        // Nested maps are merged, with dotted keys in them split up like top-level ones.
+        // Metadata keys are joined back together by `spring::translate`.
+        if let Value::Object(values) = value {
+            for (key, value) in values {
+                let mut nested: Vec<&str> = path.iter().map(String::as_str).collect();
+                nested.extend(key.split('.'));
+                self.insert(nested, value);
+            }
+            return;
+        }
+        match spring::translate(path, value) {
+            Ok(settings) => {
+                for (path, value) in settings {
+                    self.set_value(&path, value);
+                }
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn set_value(&mut self, path: &[String], value: Value) {
+        let (last, parents) = match path.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+        let mut node = &mut self.tree;
+        for segment in parents {
+            if !node.get(segment).map(Value::is_object).unwrap_or(false) {
+                node[segment.as_str()] = Value::Object(Map::new());
+            }
+            node = &mut node[segment.as_str()];
+        }
+        node[last.as_str()] = value;
+    }
+
+    /// Matches each segment of `path` against the known keys regardless of case and separators,
+    /// so that `service-url` and `SERVICEURL` both find `serviceUrl`
+    fn resolve(&self, path: &[&str]) -> Vec<String> {
+        let mut schemas = vec![&self.schema, &self.spring];
+        path.iter()
+            .map(|segment| {
+                let known = schemas
+                    .iter()
+                    .filter_map(|schema| schema.as_object())
+                    .flat_map(|keys| keys.keys())
+                    .find(|key| normalize_key(key) == normalize_key(segment));
+                let resolved = known.map(String::as_str).unwrap_or(segment).to_string();
+                schemas = schemas
+                    .iter()
+                    .filter_map(|schema| schema.get(&resolved))
+                    .collect();
+                resolved
+            })
+            .collect()
+    }
+
+    /// The default value at `path`, if it is a known setting
+    fn schema_at(&self, path: &[String]) -> Option<&Value> {
+        [&self.schema, &self.spring].iter().find_map(|schema| {
+            path.iter()
+                .try_fold(*schema, |schema, segment| schema.get(segment))
+        })
+    }
+
+    /// The defaults for `eureka.<name>` with the loaded settings merged over them
+    fn section(&self, name: &str) -> Value {
+        let mut section = self.schema["eureka"][name].clone();
+        if let Some(values) = self.tree["eureka"][name].as_object() {
+            for (key, value) in values {
+                merge(&mut section[key.as_str()], value.clone());
+            }
+        }
+        section
+    }
+
+    fn deserialize_section<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        section: Value,
+    ) -> Result<T, EurekaError> {
+        let error = match serde_json::from_value(section.clone()) {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        // serde doesn't say which field failed, so try the loaded ones one at a time
+        let defaults = &self.schema["eureka"][name];
+        let offending = section.as_object().and_then(|values| {
+            values.iter().find(|&(key, value)| {
+                let mut single = defaults.clone();
+                single[key.as_str()] = value.clone();
+                serde_json::from_value::<T>(single).is_err()
+            })
+        });
+        let key = match offending {
+            Some((key, _)) => format!("eureka.{}.{}", name, key),
+            None => format!("eureka.{}", name),
+        };
+        Err(invalid(&key, error.to_string()))
+    }
+}
+
+fn read_file(path: &Path) -> Result<Value, EurekaError> {
+    let key = path.display().to_string();
+    let contents = fs::read_to_string(path).map_err(|e| invalid(&key, e.to_string()))?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    match extension {
+        "json" => serde_json::from_str(&contents).map_err(|e| invalid(&key, e.to_string())),
+        #[cfg(feature = "yaml")]
+        "yml" | "yaml" => serde_yaml::from_str(&contents).map_err(|e| invalid(&key, e.to_string())),
+        #[cfg(feature = "toml")]
+        "toml" => toml::from_str(&contents).map_err(|e| invalid(&key, e.to_string())),
+        _ => Err(invalid(
+            &key,
+            format!("unsupported config file format `{}`", extension),
+        )),
+    }
+}
+
+/// Converts a string to the type of `schema`, leaving it as a string if that fails
+/// so that deserializing reports the error against its key
+fn coerce(value: &str, schema: Option<&Value>) -> Value {
+    match schema {
+        Some(&Value::Bool(_)) => value
+            .parse()
+            .map(Value::Bool)
+            .unwrap_or_else(|_| Value::String(value.to_string())),
+        Some(&Value::Number(_)) => serde_json::from_str(value)
+            .ok()
+            .filter(Value::is_number)
+            .unwrap_or_else(|| Value::String(value.to_string())),
+        Some(Value::Array(items)) => Value::Array(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| coerce(item, items.first()))
+                .collect(),
+        ),
+        _ => Value::String(value.to_string()),
+    }
+}
+
+fn merge(target: &mut Value, value: Value) {
+    match (target, value) {
+        (&mut Value::Object(ref mut target), Value::Object(values)) => {
+            for (key, value) in values {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, value) => *target = value,
+    }
+}
+
+/// Accepts plain numbers for ports, ports set piecemeal by Spring properties,
+/// and any scalar for metadata values
+fn normalize_instance(instance: &mut Map<String, Value>) {
+    for &(key, default_port) in &[("port", 80), ("securePort", 443)] {
+        let port = match instance.get_mut(key) {
+            Some(port) => port,
+            None => continue,
+        };
+        let port_data = match *port {
+            Value::Number(ref port) => port.as_u64().map(|port| (Value::from(port), None)),
+            Value::String(ref text) => text
+                .parse::<u64>()
+                .ok()
+                .map(|port| (Value::from(port), None)),
+            Value::Object(ref mut parts) => Some((
+                parts
+                    .remove("$")
+                    .unwrap_or_else(|| Value::from(default_port)),
+                parts.remove("@enabled"),
+            )),
+            _ => None,
+        };
+        if let Some((value, enabled)) = port_data {
+            let enabled = match enabled {
+                Some(Value::String(enabled)) => enabled,
+                Some(Value::Bool(enabled)) => enabled.to_string(),
+                _ => "true".to_string(),
+            };
+            *port = json_object(vec![("$", value), ("@enabled", Value::from(enabled))]);
+        }
+    }
+    if let Some(&mut Value::Object(ref mut metadata)) = instance.get_mut("metadata") {
+        for value in metadata.values_mut() {
+            let text = match *value {
+                Value::String(_) => continue,
+                Value::Null => String::new(),
+                ref other => other.to_string(),
+            };
+            *value = Value::String(text);
+        }
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| *c != '-' && *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn json_object(entries: Vec<(&str, Value)>) -> Value {
+    Value::Object(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("eureka-config-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn env_vars_map_onto_keys() {
+        env::set_var(
+            "EUREKA_CLIENT_SERVICEURL_DEFAULTZONE",
+            "https://eureka.test:8762/eureka/",
+        );
+        env::set_var("EUREKA_CLIENT_FETCHREGISTRY", "false");
+        env::set_var("EUREKA_INSTANCE_APP", "ORDER-SERVICE");
+        let config = ConfigLoader::new().env().load().unwrap();
+        env::remove_var("EUREKA_CLIENT_SERVICEURL_DEFAULTZONE");
+        env::remove_var("EUREKA_CLIENT_FETCHREGISTRY");
+        env::remove_var("EUREKA_INSTANCE_APP");
+
+        assert_eq!(config.eureka.host, "eureka.test");
+        assert_eq!(config.eureka.port, 8762);
+        assert!(config.eureka.ssl);
+        assert_eq!(config.eureka.service_path, "/eureka");
+        assert!(!config.eureka.fetch_registry);
+        assert_eq!(config.instance.app, "ORDER-SERVICE");
+    }
+
+    #[test]
+    fn resolves_keys_regardless_of_case_and_separators() {
+        let loader = ConfigLoader::new();
+        let expected = vec!["eureka", "client", "serviceUrl", "defaultZone"];
+        assert_eq!(
+            loader.resolve(&["EUREKA", "CLIENT", "SERVICEURL", "DEFAULTZONE"]),
+            expected
+        );
+        assert_eq!(
+            loader.resolve(&["eureka", "client", "service-url", "default_zone"]),
+            expected
+        );
+        // Unknown keys are kept as they are
+        assert_eq!(
+            loader.resolve(&["eureka", "instance", "metadata", "Zone-A"]),
+            vec!["eureka", "instance", "metadata", "Zone-A"]
+        );
+    }
+
+    #[test]
+    fn accepts_kebab_camel_and_dotted_keys() {
+        let path = temp_file(
+            "keys.json",
+            r#"{
+                "eureka.client.registry-fetch-interval": 1000,
+                "eureka": {
+                    "client": {"heartbeat.jitter": 0.2, "fetchMetadata": false},
+                    "instance.app": "ORDER-SERVICE"
+                }
+            }"#,
+        );
+        let config = ConfigLoader::new()
+            .file(&path)
+            .set("eureka.client.request-retry.max-retries-same-instance", "2")
+            .set("eureka.instance.port", "8080")
+            .load()
+            .unwrap();
+        assert_eq!(config.eureka.registry_fetch_interval, 1000);
+        assert_eq!(config.eureka.heartbeat.jitter, 0.2);
+        assert!(!config.eureka.fetch_metadata);
+        assert_eq!(config.eureka.request_retry.max_retries_same_instance, 2);
+        assert_eq!(config.instance.app, "ORDER-SERVICE");
+        assert_eq!(
+            config.instance.port.and_then(|port| port.value()),
+            Some(8080)
+        );
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let path = temp_file("layers.json", r#"{"eureka": {"client": {"port": 1}}}"#);
+        let config = ConfigLoader::new()
+            .file(&path)
+            .set("eureka.client.port", "2")
+            .load()
+            .unwrap();
+        assert_eq!(config.eureka.port, 2);
+    }
+
+    #[test]
+    fn invalid_value_reports_its_key() {
+        let error = ConfigLoader::new()
+            .set("eureka.client.fetchRegistry", "true")
+            .set("eureka.client.port", "not-a-port")
+            .load()
+            .unwrap_err();
+        match error {
+            EurekaError::InvalidConfig { key, .. } => assert_eq!(key, "eureka.client.port"),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn missing_file_is_reported() {
+        assert!(ConfigLoader::new()
+            .file("/nonexistent/eureka.json")
+            .load()
+            .is_err());
+        assert!(ConfigLoader::new()
+            .optional_file("/nonexistent/eureka.json")
+            .load()
+            .is_ok());
+    }
+
+    #[test]
+    fn coerces_strings_to_the_type_of_the_default() {
+        assert_eq!(coerce("true", Some(&Value::Bool(false))), Value::Bool(true));
+        assert_eq!(coerce("42", Some(&Value::from(0))), Value::from(42));
+        assert_eq!(coerce("yes", Some(&Value::Bool(false))), Value::from("yes"));
+        assert_eq!(
+            coerce("502, 503,", Some(&Value::Array(vec![Value::from(0)]))),
+            Value::Array(vec![Value::from(502), Value::from(503)])
+        );
+        assert_eq!(coerce("42", None), Value::from("42"));
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn reads_yaml() {
+        let path = temp_file(
+            "config.yml",
+            "eureka:\n  client:\n    serviceUrl:\n      defaultZone: http://eureka.test:8761/eureka\n\
+             \x20 instance:\n    app: ORDER-SERVICE\n",
+        );
+        let config = ConfigLoader::new().file(&path).load().unwrap();
+        assert_eq!(config.eureka.host, "eureka.test");
+        assert_eq!(config.instance.app, "ORDER-SERVICE");
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn keeps_dots_in_yaml_metadata_keys() {
+        let path = temp_file(
+            "metadata.yml",
+            "eureka:\n  instance:\n    metadataMap:\n      management:\n        port: 8081\n\
+             \x20     management.context-path: /admin\n",
+        );
+        let metadata = ConfigLoader::new()
+            .file(&path)
+            .load()
+            .unwrap()
+            .instance
+            .metadata
+            .unwrap();
+        assert_eq!(metadata["management.port"], "8081");
+        assert_eq!(metadata["management.context-path"], "/admin");
+    }
+}
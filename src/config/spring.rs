@@ -0,0 +1,214 @@
+use serde_json::Value;
+
+use super::json_object;
+use builder::invalid;
+use EurekaError;
+
+/// Spring Cloud Netflix properties that don't match our own names or units,
+/// with a value of the type each one takes
+pub fn schema() -> Value {
+    let client = json_object(vec![
+        ("registryFetchIntervalSeconds", Value::from(0)),
+        ("eurekaServiceUrlPollIntervalSeconds", Value::from(0)),
+        ("eurekaServerConnectTimeoutSeconds", Value::from(0)),
+        ("eurekaServerReadTimeoutSeconds", Value::from(0)),
+        ("preferSameZoneEureka", Value::from(false)),
+        ("useDnsForFetchingServiceUrls", Value::from(false)),
+        ("filterOnlyUpInstances", Value::from(false)),
+    ]);
+    let instance = json_object(vec![
+        ("leaseRenewalIntervalInSeconds", Value::from(0)),
+        ("leaseExpirationDurationInSeconds", Value::from(0)),
+        ("preferIpAddress", Value::from(false)),
+        ("metadataMap", json_object(vec![])),
+        ("appname", Value::from("")),
+        ("ipAddress", Value::from("")),
+        ("nonSecurePort", Value::from(0)),
+        ("nonSecurePortEnabled", Value::from(false)),
+        ("securePortEnabled", Value::from(false)),
+        ("virtualHostName", Value::from("")),
+        ("secureVirtualHostName", Value::from("")),
+    ]);
+    json_object(vec![
+        (
+            "eureka",
+            json_object(vec![("client", client), ("instance", instance)]),
+        ),
+        (
+            "spring",
+            json_object(vec![(
+                "application",
+                json_object(vec![("name", Value::from(""))]),
+            )]),
+        ),
+        ("server", json_object(vec![("port", Value::from(0))])),
+    ])
+}
+
+/// Maps a Spring property onto the settings it corresponds to,
+/// or onto none with a warning if it isn't supported.
+/// Anything else is returned unchanged.
+pub fn translate(
+    path: Vec<String>,
+    value: Value,
+) -> Result<Vec<(Vec<String>, Value)>, EurekaError> {
+    let client = |key: &'static str| vec!["eureka", "client", key];
+    let instance = |key: &'static str| vec!["eureka", "instance", key];
+    let segments: Vec<&str> = path.iter().map(String::as_str).collect();
+    let translated = match segments.as_slice() {
+        ["eureka", "client", "registryFetchIntervalSeconds"] => {
+            vec![(client("registryFetchInterval"), millis(&path, &value)?)]
+        }
+        ["eureka", "client", "eurekaServerConnectTimeoutSeconds"] => {
+            eureka_timeouts("connect", millis(&path, &value)?)
+        }
+        ["eureka", "client", "eurekaServerReadTimeoutSeconds"] => {
+            eureka_timeouts("read", millis(&path, &value)?)
+        }
+        ["eureka", "client", "eurekaServiceUrlPollIntervalSeconds"]
+        | ["eureka", "client", "preferSameZoneEureka"]
+        | ["eureka", "client", "useDnsForFetchingServiceUrls"] => {
+            warn!(
+                "{} is not supported, the Eureka server is always the one in serviceUrl.defaultZone",
+                path.join(".")
+            );
+            vec![]
+        }
+        ["eureka", "client", "filterOnlyUpInstances"] => {
+            vec![(client("filterUpInstances"), value)]
+        }
+        ["eureka", "instance", "leaseRenewalIntervalInSeconds"] => vec![(
+            vec!["eureka", "instance", "leaseInfo", "renewalIntervalInSecs"],
+            value,
+        )],
+        ["eureka", "instance", "leaseExpirationDurationInSeconds"] => vec![(
+            vec!["eureka", "instance", "leaseInfo", "durationInSecs"],
+            value,
+        )],
+        ["eureka", "instance", "preferIpAddress"] => vec![(client("preferIpAddress"), value)],
+        ["eureka", "instance", "metadataMap", key @ ..]
+        | ["eureka", "instance", "metadata", key @ ..]
+            if !key.is_empty() =>
+        {
+            // Metadata keys may contain dots themselves, as in Spring's `management.port`
+            let path = vec!["eureka", "instance", "metadata"]
+                .into_iter()
+                .map(str::to_string)
+                .chain(Some(key.join(".")))
+                .collect();
+            return Ok(vec![(path, value)]);
+        }
+        ["eureka", "instance", "appname"] | ["spring", "application", "name"] => {
+            vec![(instance("app"), value)]
+        }
+        ["eureka", "instance", "ipAddress"] => vec![(instance("ipAddr"), value)],
+        ["eureka", "instance", "nonSecurePort"] | ["server", "port"] => {
+            vec![(vec!["eureka", "instance", "port", "$"], value)]
+        }
+        ["eureka", "instance", "nonSecurePortEnabled"] => {
+            vec![(vec!["eureka", "instance", "port", "@enabled"], value)]
+        }
+        ["eureka", "instance", "securePortEnabled"] => {
+            vec![(vec!["eureka", "instance", "securePort", "@enabled"], value)]
+        }
+        ["eureka", "instance", "virtualHostName"] => vec![(instance("vipAddress"), value)],
+        ["eureka", "instance", "secureVirtualHostName"] => {
+            vec![(instance("secureVipAddress"), value)]
+        }
+        _ => return Ok(vec![(path, value)]),
+    };
+    Ok(translated
+        .into_iter()
+        .map(|(path, value)| (path.into_iter().map(str::to_string).collect(), value))
+        .collect())
+}
+
+/// Spring sets a single timeout for all requests to Eureka
+fn eureka_timeouts(kind: &'static str, value: Value) -> Vec<(Vec<&'static str>, Value)> {
+    ["registration", "heartbeat", "registryFetch"]
+        .iter()
+        .map(|request| {
+            (
+                vec!["eureka", "client", "timeouts", *request, kind],
+                value.clone(),
+            )
+        })
+        .collect()
+}
+
+fn millis(path: &[String], value: &Value) -> Result<Value, EurekaError> {
+    value
+        .as_u64()
+        .map(|seconds| Value::from(seconds * 1000))
+        .ok_or_else(|| invalid(&path.join("."), format!("expected seconds, got {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use config::ConfigLoader;
+
+    #[test]
+    fn maps_spring_properties() {
+        let config = ConfigLoader::new()
+            .set("eureka.client.registryFetchIntervalSeconds", "15")
+            .set("eureka.client.eurekaServerReadTimeoutSeconds", "8")
+            .set("eureka.client.filterOnlyUpInstances", "false")
+            .set("eureka.instance.leaseRenewalIntervalInSeconds", "10")
+            .set("eureka.instance.preferIpAddress", "true")
+            .set("eureka.instance.metadataMap.zone", "eu-west-1a")
+            .set("spring.application.name", "ORDER-SERVICE")
+            .set("server.port", "8080")
+            .load()
+            .unwrap();
+        assert_eq!(config.eureka.registry_fetch_interval, 15_000);
+        assert_eq!(config.eureka.timeouts.heartbeat.read, 8_000);
+        assert_eq!(config.eureka.timeouts.registry_fetch.read, 8_000);
+        assert!(!config.eureka.filter_up_instances);
+        assert!(config.eureka.prefer_ip_address);
+        assert_eq!(
+            config.instance.lease_info.unwrap().renewal_interval_in_secs,
+            Some(10)
+        );
+        assert_eq!(config.instance.metadata.unwrap()["zone"], "eu-west-1a");
+        assert_eq!(config.instance.app, "ORDER-SERVICE");
+        assert_eq!(
+            config.instance.port.and_then(|port| port.value()),
+            Some(8080)
+        );
+    }
+
+    #[test]
+    fn keeps_dots_in_metadata_keys() {
+        let metadata = ConfigLoader::new()
+            .set("eureka.instance.metadataMap.management.port", "8081")
+            .set("eureka.instance.metadata.git.commit.id", "abc123")
+            .load()
+            .unwrap()
+            .instance
+            .metadata
+            .unwrap();
+        assert_eq!(metadata["management.port"], "8081");
+        assert_eq!(metadata["git.commit.id"], "abc123");
+    }
+
+    #[test]
+    fn ignores_unsupported_properties() {
+        let config = ConfigLoader::new()
+            .set("eureka.client.useDnsForFetchingServiceUrls", "true")
+            .set("eureka.client.preferSameZoneEureka", "false")
+            .set("eureka.client.eurekaServiceUrlPollIntervalSeconds", "60")
+            .load()
+            .unwrap();
+        assert!(!config.eureka.use_dns);
+        assert!(config.eureka.prefer_same_zone);
+        assert_eq!(config.eureka.cluster_refresh_interval, 300_000);
+    }
+
+    #[test]
+    fn rejects_non_numeric_seconds() {
+        assert!(ConfigLoader::new()
+            .set("eureka.client.registryFetchIntervalSeconds", "soon")
+            .load()
+            .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use random_u64;
+
+/// Controls when heartbeats are sent, on top of `LeaseInfo::renewal_interval_in_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HeartbeatConfig {
+    /// Fraction of the interval, between 0 and 1, by which each heartbeat is randomly moved
+    /// so that replicas started together don't send their heartbeats together
+    pub jitter: f64,
+    /// After consecutive failures the delay is doubled, up to this many times the interval,
+    /// as in Eureka's Java client
+    pub max_backoff_multiplier: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            jitter: 0.1,
+            max_backoff_multiplier: 10,
+        }
+    }
+}
+
+/// The outcome of recent heartbeats, from `EurekaClient::heartbeat_status`
+#[derive(Debug, Clone, Default)]
+pub struct HeartbeatStatus {
+    pub consecutive_failures: usize,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+}
+
+impl HeartbeatStatus {
+    pub(crate) fn record(&mut self, success: bool) {
+        if success {
+            self.consecutive_failures = 0;
+            self.last_success = Some(SystemTime::now());
+        } else {
+            self.consecutive_failures += 1;
+            self.last_failure = Some(SystemTime::now());
+        }
+    }
+}
+
+/// Schedules heartbeats on fixed ticks, so that time spent sending them doesn't add up
+#[derive(Debug)]
+pub struct HeartbeatSchedule {
+    config: HeartbeatConfig,
+    interval: Duration,
+    next_tick: Instant,
+}
+
+impl HeartbeatSchedule {
+    pub fn new(config: HeartbeatConfig, interval: Duration) -> Self {
+        HeartbeatSchedule {
+            config,
+            interval,
+            next_tick: Instant::now() + interval,
+        }
+    }
+
+    /// How long to wait before sending the next heartbeat
+    pub fn delay(&self) -> Duration {
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        let random = random_u64() as f64 / u64::MAX as f64 - 0.5;
+        let offset = self.interval.as_secs_f64() * jitter * random;
+        let now = Instant::now();
+        let beat = if offset >= 0.0 {
+            self.next_tick + Duration::from_secs_f64(offset)
+        } else {
+            self.next_tick
+                .checked_sub(Duration::from_secs_f64(-offset))
+                .unwrap_or(now)
+        };
+        beat.saturating_duration_since(now)
+    }
+
+    /// Moves on to the next tick, backing off after `consecutive_failures` failed heartbeats
+    pub fn advance(&mut self, consecutive_failures: usize) {
+        let exponent = consecutive_failures.min(31) as u32;
+        let multiplier = (1u32 << exponent).min(self.config.max_backoff_multiplier.max(1));
+        self.next_tick += self.interval * multiplier;
+        // Skip ticks missed while a heartbeat was slow, rather than sending a burst to catch up
+        let now = Instant::now();
+        if self.next_tick < now {
+            let behind = now.duration_since(self.next_tick).as_nanos();
+            let missed = behind / self.interval.as_nanos().max(1) + 1;
+            self.next_tick += self.interval * missed.min(u128::from(u32::MAX)) as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3_600);
+
+    fn schedule(jitter: f64, interval: Duration) -> HeartbeatSchedule {
+        let config = HeartbeatConfig {
+            jitter,
+            max_backoff_multiplier: 10,
+        };
+        HeartbeatSchedule::new(config, interval)
+    }
+
+    #[test]
+    fn advances_by_one_interval_on_success() {
+        let mut schedule = schedule(0.0, HOUR);
+        let first = schedule.next_tick;
+        schedule.advance(0);
+        assert_eq!(schedule.next_tick, first + HOUR);
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let mut schedule = schedule(0.0, HOUR);
+        let mut expected = schedule.next_tick;
+        for &(failures, multiplier) in &[(1, 2), (2, 4), (3, 8), (4, 10), (40, 10)] {
+            schedule.advance(failures);
+            expected += HOUR * multiplier;
+            assert_eq!(schedule.next_tick, expected, "{} failures", failures);
+        }
+    }
+
+    #[test]
+    fn skips_missed_ticks() {
+        let interval = Duration::from_millis(10);
+        let mut schedule = schedule(0.0, interval);
+        schedule.next_tick = Instant::now() - Duration::from_millis(35);
+        schedule.advance(0);
+        let now = Instant::now();
+        assert!(schedule.next_tick > now - interval);
+        assert!(schedule.next_tick <= now + interval);
+    }
+
+    #[test]
+    fn delay_stays_within_jitter() {
+        let jittered = schedule(0.5, HOUR);
+        for _ in 0..100 {
+            let delay = jittered.delay();
+            assert!(delay >= HOUR * 3 / 4 - Duration::from_secs(1));
+            assert!(delay <= HOUR * 5 / 4);
+        }
+        let without_jitter = schedule(0.0, HOUR);
+        assert!(without_jitter.delay() <= HOUR);
+        assert!(without_jitter.delay() >= HOUR - Duration::from_secs(1));
+    }
+
+    #[test]
+    fn status_counts_consecutive_failures() {
+        let mut status = HeartbeatStatus::default();
+        status.record(false);
+        status.record(false);
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.last_failure.is_some() && status.last_success.is_none());
+        status.record(true);
+        assert_eq!(status.consecutive_failures, 0);
+        assert!(status.last_success.is_some());
+    }
+}
@@ -0,0 +1,204 @@
+use reqwest::{Certificate, Client, Identity};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use EurekaError;
+
+/// Timeouts for one kind of request, in milliseconds. Zero disables the timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeoutConfig {
+    /// Time allowed to establish a connection
+    pub connect: usize,
+    /// Time allowed for the whole request, from sending it to reading the full response
+    pub read: usize,
+}
+
+impl TimeoutConfig {
+    pub fn new(connect: usize, read: usize) -> Self {
+        TimeoutConfig { connect, read }
+    }
+}
+
+/// Timeouts for each kind of request the client makes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Timeouts {
+    /// Registering, deregistering and status or metadata updates
+    pub registration: TimeoutConfig,
+    pub heartbeat: TimeoutConfig,
+    pub registry_fetch: TimeoutConfig,
+    /// Requests to other apps made through `EurekaClient::make_request`
+    pub application: TimeoutConfig,
+}
+
+/// The same for every kind of request, so that they all share one connection pool
+impl Default for Timeouts {
+    fn default() -> Self {
+        let timeout = TimeoutConfig::new(5_000, 30_000);
+        Timeouts {
+            registration: timeout,
+            heartbeat: timeout,
+            registry_fetch: timeout,
+            application: timeout,
+        }
+    }
+}
+
+/// TLS settings applied to every HTTP client, both for Eureka and for requests to other apps.
+///
+/// reqwest 0.9 can't set a minimum TLS version or override the SNI server name, so the
+/// system TLS library's defaults apply, and the name sent is always the host in the URL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TlsConfig {
+    /// Files with extra CA certificates to trust, in PEM or DER format.
+    /// A PEM file may contain several certificates.
+    pub ca_certs: Vec<String>,
+    /// PKCS#12 file holding the client certificate and private key, for mutual TLS.
+    /// PEM identities aren't supported by reqwest 0.9's native TLS backend,
+    /// convert them with `openssl pkcs12 -export`.
+    pub client_identity: Option<String>,
+    /// Password for `client_identity`
+    pub client_identity_password: String,
+    /// Skips checking that certificates match the host name. Dangerous, only for testing.
+    pub accept_invalid_hostnames: bool,
+    /// Accepts any certificate, including self-signed and expired ones. Dangerous, only for testing.
+    pub accept_invalid_certs: bool,
+}
+
+/// Hands out HTTP clients, sharing one connection pool between all uses with the same timeouts.
+///
+/// reqwest 0.9 only supports timeouts per client, so each distinct timeout needs its own client,
+/// with its own pool. The default timeouts are all the same, so by default there is a single pool.
+/// A user-supplied client replaces all of them, along with their timeouts and TLS settings.
+#[derive(Debug)]
+pub struct HttpClients {
+    tls: TlsConfig,
+    custom: Option<Client>,
+    clients: Mutex<HashMap<TimeoutConfig, Client>>,
+}
+
+impl HttpClients {
+    pub fn new(tls: TlsConfig, custom: Option<Client>) -> Self {
+        HttpClients {
+            tls,
+            custom,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a user-supplied client is used, which ignores our timeouts
+    pub fn is_custom(&self) -> bool {
+        self.custom.is_some()
+    }
+
+    pub fn get(&self, timeout: TimeoutConfig) -> Result<Client, EurekaError> {
+        if let Some(ref client) = self.custom {
+            return Ok(client.clone());
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&timeout) {
+            return Ok(client.clone());
+        }
+        let client = build_client(timeout, &self.tls)?;
+        clients.insert(timeout, client.clone());
+        Ok(client)
+    }
+}
+
+pub fn build_client(timeout: TimeoutConfig, tls: &TlsConfig) -> Result<Client, EurekaError> {
+    let mut builder = Client::builder()
+        .timeout(millis(timeout.read))
+        .connect_timeout(millis(timeout.connect))
+        .danger_accept_invalid_hostnames(tls.accept_invalid_hostnames)
+        .danger_accept_invalid_certs(tls.accept_invalid_certs);
+    for path in &tls.ca_certs {
+        for cert in load_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(ref path) = tls.client_identity {
+        let der = read_file("eureka.tls.clientIdentity", path)?;
+        let identity = Identity::from_pkcs12_der(&der, &tls.client_identity_password)
+            .map_err(|e| invalid_tls_file("eureka.tls.clientIdentity", path, e))?;
+        builder = builder.identity(identity);
+    }
+    builder.build().map_err(EurekaError::from)
+}
+
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, EurekaError> {
+    const PEM_HEADER: &str = "-----BEGIN CERTIFICATE-----";
+
+    let contents = read_file("eureka.tls.caCerts", path)?;
+    let pem = String::from_utf8_lossy(&contents);
+    if !pem.contains(PEM_HEADER) {
+        return Certificate::from_der(&contents)
+            .map(|cert| vec![cert])
+            .map_err(|e| invalid_tls_file("eureka.tls.caCerts", path, e));
+    }
+    // `Certificate::from_pem` only reads the first certificate, so split bundles up front
+    pem.match_indices(PEM_HEADER)
+        .map(|(start, _)| &pem[start..])
+        .map(|block| {
+            let block = block[PEM_HEADER.len()..]
+                .find(PEM_HEADER)
+                .map(|end| &block[..PEM_HEADER.len() + end])
+                .unwrap_or(block);
+            Certificate::from_pem(block.as_bytes())
+                .map_err(|e| invalid_tls_file("eureka.tls.caCerts", path, e))
+        })
+        .collect()
+}
+
+fn read_file(key: &str, path: &str) -> Result<Vec<u8>, EurekaError> {
+    fs::read(path).map_err(|e| invalid_tls_file(key, path, e))
+}
+
+fn invalid_tls_file<E: ::std::fmt::Display>(key: &str, path: &str, err: E) -> EurekaError {
+    EurekaError::InvalidConfig {
+        key: key.to_string(),
+        reason: format!("{}: {}", path, err),
+    }
+}
+
+fn millis(value: usize) -> Option<Duration> {
+    if value == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(value as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_timeouts_share_one_client() {
+        let http = HttpClients::new(TlsConfig::default(), None);
+        let timeouts = Timeouts::default();
+        for &timeout in &[
+            timeouts.registration,
+            timeouts.heartbeat,
+            timeouts.registry_fetch,
+            timeouts.application,
+        ] {
+            http.get(timeout).unwrap();
+        }
+        assert_eq!(http.clients.lock().unwrap().len(), 1);
+
+        http.get(TimeoutConfig::new(1_000, 1_000)).unwrap();
+        assert_eq!(http.clients.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn custom_client_replaces_all_others() {
+        let http = HttpClients::new(TlsConfig::default(), Some(Client::new()));
+        assert!(http.is_custom());
+        http.get(TimeoutConfig::new(1_000, 1_000)).unwrap();
+        assert!(http.clients.lock().unwrap().is_empty());
+    }
+}
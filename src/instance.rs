@@ -1,29 +1,63 @@
+use aws::AwsMetadata;
+use heartbeat::{HeartbeatConfig, HeartbeatSchedule, HeartbeatStatus};
+use reqwest::Url;
+use rest::structures::{AmazonMetadataType, DataCenterInfo, DcNameType};
 pub use rest::structures::{Instance, PortData, StatusType};
 use rest::EurekaRestClient;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
-use EurekaError;
+use {current_time_millis, random_u64, EurekaConfig, EurekaError, RequestTarget};
 
 #[derive(Debug)]
 pub struct InstanceClient {
     client: Arc<EurekaRestClient>,
-    config: Arc<Instance>,
+    config: Arc<RwLock<Instance>>,
+    /// Claimed by the first call to `start`, so that concurrent calls don't register twice
+    is_starting: AtomicBool,
+    /// Set once registered
     is_running: Arc<AtomicBool>,
+    heartbeat: HeartbeatConfig,
+    heartbeat_status: Arc<Mutex<HeartbeatStatus>>,
+    use_local_metadata: bool,
+    prefer_ip_address: bool,
+    /// Set when the instance ID was expanded from it, so that it can be expanded again
+    /// once the instance's AWS addresses are known
+    instance_id_template: Option<String>,
 }
 
 impl InstanceClient {
-    pub fn new(base_url: String, config: Instance) -> Self {
+    pub fn new(
+        client: Arc<EurekaRestClient>,
+        mut config: Instance,
+        eureka: &EurekaConfig,
+        instance_id_template: Option<String>,
+    ) -> Self {
+        config.last_dirty_timestamp = Some(current_time_millis());
         InstanceClient {
-            client: Arc::new(EurekaRestClient::new(base_url)),
-            config: Arc::new(config),
+            client,
+            config: Arc::new(RwLock::new(config)),
+            is_starting: AtomicBool::new(false),
             is_running: Arc::new(AtomicBool::new(false)),
+            heartbeat: eureka.heartbeat.clone(),
+            heartbeat_status: Arc::new(Mutex::new(HeartbeatStatus::default())),
+            use_local_metadata: eureka.use_local_metadata,
+            prefer_ip_address: eureka.prefer_ip_address,
+            instance_id_template,
         }
     }
 
+    pub fn heartbeat_status(&self) -> HeartbeatStatus {
+        self.heartbeat_status.lock().unwrap().clone()
+    }
+
     pub fn start(&self) {
-        while let Err(e) = self.client.register(&self.config.app, &*self.config) {
+        if self.is_starting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        while let Err(e) = register(&self.client, &self.config) {
             error!("Failed to register app: {}", e);
             thread::sleep(Duration::from_secs(15));
         }
@@ -34,41 +68,474 @@ impl InstanceClient {
         let is_running = Arc::clone(&self.is_running);
         let client = Arc::clone(&self.client);
         let config = Arc::clone(&self.config);
+        let heartbeat = self.heartbeat.clone();
+        let heartbeat_status = Arc::clone(&self.heartbeat_status);
         thread::spawn(move || {
-            thread::sleep(Duration::from_secs(30));
-            while is_running.load(Ordering::Relaxed) {
-                let resp = client.send_heartbeat(&config.app, &config.host_name);
-                match resp {
-                    Err(EurekaError::UnexpectedState(_)) => {
-                        warn!("App not registered with eureka, reregistering");
-                        let _ = client.register(&config.app, &*config);
-                    }
-                    Err(e) => {
-                        error!("Failed to send heartbeat: {}", e);
-                    }
-                    Ok(_) => {
-                        debug!("Sent heartbeat successfully");
-                    }
+            let interval = config
+                .read()
+                .unwrap()
+                .lease_info
+                .map(|lease| lease.renewal_interval())
+                .filter(|interval| *interval > Duration::from_secs(0))
+                .unwrap_or_else(|| Duration::from_secs(30));
+            let mut schedule = HeartbeatSchedule::new(heartbeat, interval);
+            loop {
+                thread::sleep(schedule.delay());
+                if !is_running.load(Ordering::Relaxed) {
+                    break;
                 }
-                thread::sleep(Duration::from_secs(30));
+                let resp = {
+                    let instance = config.read().unwrap();
+                    client.send_heartbeat(
+                        &instance.app,
+                        instance.id(),
+                        instance.status,
+                        instance.last_dirty_timestamp,
+                    )
+                };
+                let success = handle_heartbeat(&client, &config, resp);
+                let consecutive_failures = {
+                    let mut status = heartbeat_status.lock().unwrap();
+                    status.record(success);
+                    status.consecutive_failures
+                };
+                schedule.advance(consecutive_failures);
             }
         });
 
-        while let Err(e) =
-            self.client
-                .update_status(&self.config.app, &self.config.host_name, StatusType::Up)
-        {
-            error!("Failed to set app to UP: {}", e);
-            thread::sleep(Duration::from_secs(15));
+        self.update(|instance| instance.status = StatusType::Up);
+        loop {
+            let result = {
+                // Sending our new dirty timestamp keeps the next heartbeat from being
+                // rejected as stale, which would make us register all over again
+                let instance = self.config.read().unwrap();
+                self.client.put_status(
+                    &instance.app,
+                    instance.id(),
+                    instance.status,
+                    instance.last_dirty_timestamp,
+                )
+            };
+            match result {
+                Ok(()) => break,
+                Err(e) => {
+                    error!("Failed to set app to UP: {}", e);
+                    thread::sleep(Duration::from_secs(15));
+                }
+            }
         }
     }
+
+    /// For instances in an Amazon data center, looks up the instance's AWS metadata
+    /// and advertises its public host name and IP address, or its local ones if `use_local_metadata` is set
+    pub fn load_aws_metadata(&self) {
+        if self.config.read().unwrap().data_center_info.name != DcNameType::Amazon {
+            return;
+        }
+        self.apply_aws_metadata(AwsMetadata::new(&HashMap::new()).amazon_metadata());
+    }
+
+    /// Advertises the addresses in `metadata`, and derives the URLs and instance ID from them
+    /// in place of the ones derived from the addresses the instance was built with
+    fn apply_aws_metadata(&self, metadata: AmazonMetadataType) {
+        self.update(|instance| {
+            let (host_name, ip_addr) = if self.use_local_metadata {
+                (&metadata.local_hostname, &metadata.local_ipv4)
+            } else {
+                (&metadata.public_hostname, &metadata.public_ipv4)
+            };
+            // Instances without a public address only have local ones
+            let host_name = Some(host_name)
+                .filter(|host_name| !host_name.is_empty())
+                .unwrap_or(&metadata.local_hostname);
+            let ip_addr = Some(ip_addr)
+                .filter(|ip_addr| !ip_addr.is_empty())
+                .unwrap_or(&metadata.local_ipv4);
+            if !host_name.is_empty() {
+                let old_host_name = instance.host_name.clone();
+                replace_url_hosts(instance, &old_host_name, host_name);
+                instance.host_name = host_name.clone();
+            }
+            if !ip_addr.is_empty() {
+                let old_ip_addr = instance.ip_addr.clone();
+                replace_url_hosts(instance, &old_ip_addr, ip_addr);
+                instance.ip_addr = ip_addr.clone();
+            }
+            if self.prefer_ip_address {
+                prefer_ip_address(instance);
+            }
+            if let Some(ref template) = self.instance_id_template {
+                instance.instance_id = Some(expand_instance_id(template, instance));
+            }
+            instance.data_center_info = DataCenterInfo::amazon(metadata.clone());
+        });
+    }
+
+    /// Applies a change to the local instance data and marks it as dirty,
+    /// so that Eureka can tell our copy apart from an older one
+    fn update<F: FnOnce(&mut Instance)>(&self, change: F) {
+        let mut instance = self.config.write().unwrap();
+        change(&mut instance);
+        instance.last_dirty_timestamp = Some(current_time_millis());
+    }
 }
 
 impl Drop for InstanceClient {
     fn drop(&mut self) {
-        self.is_running.store(false, Ordering::Relaxed);
-        let _ = self
-            .client
-            .deregister(&self.config.app, &self.config.host_name);
+        // Only deregister if we ever registered
+        if !self.is_running.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let instance = self.config.read().unwrap();
+        let _ = self.client.deregister(&instance.app, instance.id());
+    }
+}
+
+fn register(client: &EurekaRestClient, config: &RwLock<Instance>) -> Result<(), EurekaError> {
+    let instance = config.read().unwrap();
+    client.register(&instance.app, &instance)
+}
+
+/// Acts on the server's answer to a heartbeat, returning whether it succeeded
+fn handle_heartbeat(
+    client: &EurekaRestClient,
+    config: &RwLock<Instance>,
+    resp: Result<Option<Instance>, EurekaError>,
+) -> bool {
+    match resp {
+        Err(EurekaError::NotFound { .. }) => {
+            warn!("App not registered with eureka, reregistering");
+            register(client, config).is_ok()
+        }
+        Err(EurekaError::Conflict { .. }) => {
+            warn!("Eureka has a conflicting copy of this instance, reregistering");
+            register(client, config).is_ok()
+        }
+        Err(e) => {
+            error!("Failed to send heartbeat: {}", e);
+            false
+        }
+        Ok(Some(remote)) => {
+            debug!("Sent heartbeat successfully");
+            sync_from_remote(config, remote);
+            true
+        }
+        Ok(None) => {
+            debug!("Sent heartbeat successfully");
+            true
+        }
+    }
+}
+
+/// Takes on the server's copy of our instance if it is newer than the local one
+fn sync_from_remote(config: &RwLock<Instance>, remote: Instance) {
+    let mut instance = config.write().unwrap();
+    if remote.last_dirty_timestamp > instance.last_dirty_timestamp {
+        debug!("Syncing newer instance data from eureka");
+        instance.status = remote.status;
+        instance.metadata = remote.metadata;
+        instance.last_dirty_timestamp = remote.last_dirty_timestamp;
+    }
+}
+
+/// Advertises the IP address in place of the host name, both on its own and in the instance's URLs
+pub fn prefer_ip_address(instance: &mut Instance) {
+    if instance.ip_addr.is_empty() || instance.ip_addr == instance.host_name {
+        return;
+    }
+    let host_name = instance.host_name.clone();
+    let ip_addr = instance.ip_addr.clone();
+    replace_url_hosts(instance, &host_name, &ip_addr);
+    instance.host_name = ip_addr;
+}
+
+/// Points the instance's URLs that use `from` as their host at `to` instead
+fn replace_url_hosts(instance: &mut Instance, from: &str, to: &str) {
+    for url in &mut [
+        &mut instance.home_page_url,
+        &mut instance.status_page_url,
+        &mut instance.health_check_url,
+    ] {
+        let mut parsed = match Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+        if parsed.host_str() == Some(from) && parsed.set_host(Some(to)).is_ok() {
+            **url = parsed.into_string();
+        }
+    }
+}
+
+/// Builds an instance ID from a template such as `{host}:{app}:{port}`.
+///
+/// Supported placeholders are `{host}`, `{ip}`, `{app}`, `{port}` and `{random}`,
+/// the last of which expands to a random hex string so that several instances
+/// on the same host never collide.
+pub fn expand_instance_id(template: &str, instance: &Instance) -> String {
+    let port = instance
+        .port
+        .as_ref()
+        .and_then(|port| port.value())
+        .or_else(|| instance.secure_port.value())
+        .map(|port| port.to_string())
+        .unwrap_or_default();
+    template
+        .replace("{host}", &instance.host_name)
+        .replace("{ip}", &instance.ip_addr)
+        .replace("{app}", &instance.app)
+        .replace("{port}", &port)
+        .replace("{random}", &format!("{:016x}", random_u64()))
+}
+
+/// The URL that paths on `instance` are relative to, without a trailing slash
+pub fn instance_base_url(
+    instance: &Instance,
+    target: RequestTarget,
+) -> Result<String, EurekaError> {
+    let no_usable_port = || EurekaError::NoUsablePort {
+        app: instance.app.clone(),
+        instance: instance.id().to_string(),
+    };
+    let host = match target {
+        RequestTarget::IpAddr => &instance.ip_addr,
+        RequestTarget::HostName => &instance.host_name,
+        RequestTarget::Vip => &instance.vip_address,
+        RequestTarget::HomePageUrl => {
+            return if instance.home_page_url.is_empty() {
+                Err(no_usable_port())
+            } else {
+                Ok(instance.home_page_url.trim_end_matches('/').to_string())
+            };
+        }
+    };
+    if let Some(port) = instance.secure_port.value() {
+        return Ok(format!("https://{}:{}", host, port));
+    }
+    match instance.port.as_ref().and_then(|port| port.value()) {
+        Some(port) => Ok(format!("http://{}:{}", host, port)),
+        None => Err(no_usable_port()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::TestServer;
+    use {BaseConfig, EurekaClient};
+
+    fn rest_client(server: &TestServer) -> Arc<EurekaRestClient> {
+        Arc::new(EurekaRestClient::new(server.url(), &EurekaConfig::default()).unwrap())
+    }
+
+    fn instance() -> Instance {
+        Instance {
+            app: "APP".to_string(),
+            instance_id: Some("app-1".to_string()),
+            last_dirty_timestamp: Some(1000),
+            ..Instance::default()
+        }
+    }
+
+    #[test]
+    fn sends_dirty_timestamp_when_going_up() {
+        let server = TestServer::new(|request| match request.method.as_str() {
+            "POST" => (204, String::new()),
+            _ => (200, String::new()),
+        });
+        let instance_client = InstanceClient::new(
+            rest_client(&server),
+            instance(),
+            &EurekaConfig::default(),
+            None,
+        );
+        instance_client.start();
+
+        let timestamp = instance_client
+            .config
+            .read()
+            .unwrap()
+            .last_dirty_timestamp
+            .unwrap();
+        let received = server.received();
+        assert_eq!(received[0].method, "POST");
+        assert_eq!(received[1].method, "PUT");
+        assert_eq!(
+            received[1].path,
+            format!(
+                "/apps/APP/app-1/status?value=UP&lastDirtyTimestamp={}",
+                timestamp
+            )
+        );
+    }
+
+    #[test]
+    fn registers_once_when_started_concurrently() {
+        let server = TestServer::new(|request| match request.method.as_str() {
+            "POST" => (204, String::new()),
+            _ => (200, String::new()),
+        });
+        let instance_client = InstanceClient::new(
+            rest_client(&server),
+            instance(),
+            &EurekaConfig::default(),
+            None,
+        );
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| instance_client.start());
+            }
+        });
+
+        let registrations = server
+            .received()
+            .iter()
+            .filter(|request| request.method == "POST")
+            .count();
+        assert_eq!(registrations, 1);
+    }
+
+    #[test]
+    fn reregisters_when_eureka_has_a_conflicting_copy() {
+        let server = TestServer::new(|request| match request.method.as_str() {
+            "POST" => (204, String::new()),
+            _ => (409, String::new()),
+        });
+        let client = rest_client(&server);
+        let config = RwLock::new(instance());
+        let resp = client.send_heartbeat("APP", "app-1", StatusType::Up, Some(1000));
+        assert!(matches!(resp, Err(EurekaError::Conflict { .. })));
+
+        assert!(handle_heartbeat(&client, &config, resp));
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].method, "POST");
+        assert_eq!(received[1].path, "/apps/APP");
+    }
+
+    #[test]
+    fn takes_on_newer_data_from_eureka() {
+        let config = RwLock::new(instance());
+        let mut metadata = HashMap::new();
+        metadata.insert("zone".to_string(), "a".to_string());
+        let remote = Instance {
+            status: StatusType::OutOfService,
+            metadata: Some(metadata.clone()),
+            last_dirty_timestamp: Some(2000),
+            ..instance()
+        };
+
+        sync_from_remote(&config, remote);
+        let synced = config.read().unwrap();
+        assert_eq!(synced.status, StatusType::OutOfService);
+        assert_eq!(synced.metadata, Some(metadata));
+        assert_eq!(synced.last_dirty_timestamp, Some(2000));
+    }
+
+    #[test]
+    fn keeps_local_data_over_older_copies() {
+        let config = RwLock::new(instance());
+        let remote = Instance {
+            status: StatusType::OutOfService,
+            last_dirty_timestamp: Some(500),
+            ..instance()
+        };
+
+        sync_from_remote(&config, remote);
+        let local = config.read().unwrap();
+        assert_eq!(local.status, StatusType::Starting);
+        assert_eq!(local.last_dirty_timestamp, Some(1000));
+    }
+
+    fn aws_client(eureka: EurekaConfig, instance_id: Option<&str>) -> EurekaClient {
+        let mut instance = Instance::builder()
+            .app("ORDER-SERVICE")
+            .host_name("localhost")
+            .ip_addr("127.0.0.1")
+            .port(8080)
+            .build()
+            .unwrap();
+        instance.instance_id = instance_id.map(str::to_string);
+        let config = BaseConfig {
+            eureka: EurekaConfig {
+                fetch_registry: false,
+                instance_id_template: "{host}:{app}:{port}".to_string(),
+                ..eureka
+            },
+            instance,
+        };
+        EurekaClient::builder().config(config).build().unwrap()
+    }
+
+    fn aws_metadata() -> AmazonMetadataType {
+        AmazonMetadataType {
+            public_hostname: "ec2-54-1-2-3.compute.amazonaws.com".to_string(),
+            public_ipv4: "54.1.2.3".to_string(),
+            local_hostname: "ip-10-0-0-11.ec2.internal".to_string(),
+            local_ipv4: "10.0.0.11".to_string(),
+            ..AmazonMetadataType::default()
+        }
+    }
+
+    #[test]
+    fn aws_addresses_replace_detected_ones() {
+        let client = aws_client(EurekaConfig::default(), None);
+        client.instance.apply_aws_metadata(aws_metadata());
+
+        let instance = client.instance.config.read().unwrap();
+        assert_eq!(instance.host_name, "ec2-54-1-2-3.compute.amazonaws.com");
+        assert_eq!(instance.ip_addr, "54.1.2.3");
+        assert_eq!(
+            instance.home_page_url,
+            "http://ec2-54-1-2-3.compute.amazonaws.com:8080/"
+        );
+        assert_eq!(
+            instance.id(),
+            "ec2-54-1-2-3.compute.amazonaws.com:ORDER-SERVICE:8080"
+        );
+        assert_eq!(instance.data_center_info.name, DcNameType::Amazon);
+    }
+
+    #[test]
+    fn aws_addresses_keep_the_ip_address_preferred() {
+        let eureka = EurekaConfig {
+            prefer_ip_address: true,
+            ..EurekaConfig::default()
+        };
+        let client = aws_client(eureka, None);
+        client.instance.apply_aws_metadata(aws_metadata());
+
+        let instance = client.instance.config.read().unwrap();
+        assert_eq!(instance.host_name, "54.1.2.3");
+        assert_eq!(instance.ip_addr, "54.1.2.3");
+        assert_eq!(instance.home_page_url, "http://54.1.2.3:8080/");
+        assert_eq!(instance.id(), "54.1.2.3:ORDER-SERVICE:8080");
+    }
+
+    #[test]
+    fn aws_local_addresses_are_used_when_asked_for_or_without_public_ones() {
+        let eureka = EurekaConfig {
+            use_local_metadata: true,
+            ..EurekaConfig::default()
+        };
+        let client = aws_client(eureka, None);
+        client.instance.apply_aws_metadata(aws_metadata());
+        assert_eq!(client.instance.config.read().unwrap().ip_addr, "10.0.0.11");
+
+        let client = aws_client(EurekaConfig::default(), None);
+        client.instance.apply_aws_metadata(AmazonMetadataType {
+            public_hostname: String::new(),
+            public_ipv4: String::new(),
+            ..aws_metadata()
+        });
+        let instance = client.instance.config.read().unwrap();
+        assert_eq!(instance.host_name, "ip-10-0-0-11.ec2.internal");
+        assert_eq!(instance.ip_addr, "10.0.0.11");
+    }
+
+    #[test]
+    fn aws_addresses_leave_explicit_instance_ids_alone() {
+        let client = aws_client(EurekaConfig::default(), Some("order-1"));
+        client.instance.apply_aws_metadata(aws_metadata());
+        assert_eq!(client.instance.config.read().unwrap().id(), "order-1");
     }
 }
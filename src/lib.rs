@@ -1,7 +1,10 @@
+extern crate base64;
 extern crate itertools;
 #[macro_use]
 extern crate log;
 extern crate percent_encoding;
+#[cfg(feature = "xml")]
+extern crate quick_xml;
 #[macro_use]
 extern crate quick_error;
 extern crate reqwest;
@@ -9,41 +12,125 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_urlencoded;
+#[cfg(feature = "yaml")]
+extern crate serde_yaml;
+#[cfg(feature = "toml")]
+extern crate toml;
 
+pub use self::auth::{AuthConfig, AuthProvider};
+use self::breaker::CircuitBreakers;
+pub use self::breaker::{BreakerSnapshot, BreakerState, CircuitBreakerConfig};
+use self::builder::validate_instance;
+pub use self::builder::{EurekaClientBuilder, InstanceBuilder};
+pub use self::config::ConfigLoader;
+pub use self::heartbeat::{HeartbeatConfig, HeartbeatStatus};
+use self::http::HttpClients;
+pub use self::http::{TimeoutConfig, Timeouts, TlsConfig};
 use self::instance::InstanceClient;
 pub use self::instance::{Instance, PortData, StatusType};
 use self::registry::RegistryClient;
-use reqwest::header::HeaderMap;
+pub use self::registry::{RegistryEvent, RegistryStatus, StaticInstancesConfig};
+pub use self::request::{AppRequest, ResponseError};
+pub use self::rest::codec::WireFormat;
+pub use self::rest::structures::{
+    ActionType, AmazonMetadataType, Application, Applications, DataCenterInfo, DcNameType,
+    LeaseInfo, StatusUpdate,
+};
+pub use self::rest::EurekaRestClient;
+pub use self::retry::RetryPolicy;
+pub use self::snapshot::RegistryCacheConfig;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT};
 use reqwest::Client as ReqwestClient;
 pub use reqwest::{Error as ReqwestError, Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
+mod auth;
 mod aws;
+mod breaker;
+mod builder;
+mod config;
+mod heartbeat;
+mod http;
 mod instance;
 mod registry;
+mod request;
+#[allow(dead_code, unused_variables)]
 mod resolver;
 mod rest;
+mod retry;
+mod snapshot;
+#[cfg(test)]
+mod test_server;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct EurekaConfig {
+    /// May include basic auth credentials as `user:password@host`,
+    /// which are moved into `auth` when the client is built
     pub host: String,
     pub port: u16,
+    /// How often heartbeats are sent, in milliseconds, unless `LeaseInfo::renewal_interval_in_secs` is set.
+    /// Eureka only supports whole seconds, so this is rounded up.
     pub heartbeat_interval: usize,
+    /// Jitter and backoff for heartbeats
+    pub heartbeat: HeartbeatConfig,
+    /// How often the registry is fetched, in milliseconds
     pub registry_fetch_interval: usize,
-    pub max_retries: usize,
-    pub request_retry_delay: usize,
+    /// Lookups of other apps fail once the registry hasn't been fetched for this many milliseconds,
+    /// unless the app has static instances. 0 keeps serving the last fetched registry indefinitely.
+    pub max_registry_age: usize,
+    /// Instances that registered within this many milliseconds get a share of requests
+    /// that ramps up over the period, so that they can warm up. 0 disables this.
+    pub slow_start_window: usize,
     pub fetch_registry: bool,
+    /// Only keep instances whose status is `UP` when fetching the registry
     pub filter_up_instances: bool,
     pub service_path: String,
     pub ssl: bool,
+    /// Not supported yet, the Eureka server is always the one at `host`
     pub use_dns: bool,
+    /// Not supported yet, the Eureka server is always the one at `host`
     pub prefer_same_zone: bool,
+    /// Not supported yet, the Eureka server is always the one at `host`
     pub cluster_refresh_interval: usize,
+    /// Whether the metadata maps of other instances are kept when fetching the registry
     pub fetch_metadata: bool,
+    /// Copy of the registry kept on disk for when Eureka is unreachable at startup
+    pub registry_cache: RegistryCacheConfig,
+    /// Fixed instances for apps, used when Eureka is disabled or down, or merged with its instances
+    pub static_instances: StaticInstancesConfig,
     pub register_with_eureka: bool,
+    /// For instances in an Amazon data center, advertise the local host name and IP address
+    /// from the AWS metadata service rather than the public ones
     pub use_local_metadata: bool,
+    /// Advertise the instance's IP address as its host name and in its URLs
     pub prefer_ip_address: bool,
+    /// Template used to build the instance ID when `instance.instance_id` is not set,
+    /// see `expand_instance_id` for the supported placeholders
+    pub instance_id_template: String,
+    /// Body format used when talking to Eureka. XML requires the `xml` feature.
+    pub wire_format: WireFormat,
+    /// Retry behavior for `EurekaClient::make_request`
+    pub request_retry: RetryPolicy,
+    /// Ejection of failing instances for `EurekaClient::make_request`
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub timeouts: Timeouts,
+    /// Only used when connecting over https, either to Eureka or to other apps
+    pub tls: TlsConfig,
+    /// Credentials for Eureka. These can also be embedded in `host`, as in `user:password@host`.
+    pub auth: Option<AuthConfig>,
+    /// Custom credentials for Eureka, taking precedence over `auth`
+    #[serde(skip)]
+    pub auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// How `EurekaClient::make_request` addresses the instances it sends requests to
+    pub request_target: RequestTarget,
 }
 
 impl Default for EurekaConfig {
@@ -52,9 +139,10 @@ impl Default for EurekaConfig {
             host: "localhost".to_string(),
             port: 8761,
             heartbeat_interval: 30_000,
+            heartbeat: HeartbeatConfig::default(),
             registry_fetch_interval: 30_000,
-            max_retries: 3,
-            request_retry_delay: 500,
+            max_registry_age: 0,
+            slow_start_window: 0,
             fetch_registry: true,
             filter_up_instances: true,
             service_path: "/eureka".to_string(),
@@ -63,14 +151,41 @@ impl Default for EurekaConfig {
             prefer_same_zone: true,
             cluster_refresh_interval: 300_000,
             fetch_metadata: true,
+            registry_cache: RegistryCacheConfig::default(),
+            static_instances: StaticInstancesConfig::default(),
             register_with_eureka: true,
             use_local_metadata: false,
             prefer_ip_address: false,
+            instance_id_template: "{host}:{app}:{port}".to_string(),
+            wire_format: WireFormat::Json,
+            request_retry: RetryPolicy::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            timeouts: Timeouts::default(),
+            tls: TlsConfig::default(),
+            auth: None,
+            auth_provider: None,
+            request_target: RequestTarget::IpAddr,
         }
     }
 }
 
+/// Which of an instance's advertised addresses requests to it are sent to.
+///
+/// For all but `HomePageUrl`, the scheme and port follow the instance's advertised ports,
+/// preferring the secure port when it is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RequestTarget {
+    IpAddr,
+    HostName,
+    /// The instance's VIP address, for VIPs that resolve through DNS
+    Vip,
+    /// The instance's home page URL, used as is
+    HomePageUrl,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct BaseConfig {
     pub eureka: EurekaConfig,
     pub instance: Instance,
@@ -80,104 +195,333 @@ quick_error! {
     #[derive(Debug)]
     pub enum EurekaError {
         Network(err: ReqwestError) {
-            description(err.description())
-            cause(err)
+            display("Network error: {}", err)
+            source(err)
+        }
+        Timeout(err: ReqwestError) {
+            display("Request timed out: {}", err)
+            source(err)
+        }
+        /// The app, or the given instance of it, is not known to Eureka.
+        /// `app` is only missing when an instance was looked up by ID alone.
+        NotFound { app: Option<String>, instance: Option<String> } {
+            display("{} not found", match (app, instance) {
+                (Some(app), Some(instance)) => format!("Instance {} of app {}", instance, app),
+                (Some(app), None) => format!("App {}", app),
+                (None, Some(instance)) => format!("Instance {}", instance),
+                (None, None) => "Resource".to_string(),
+            })
+        }
+        /// Eureka's copy of the instance is newer than the one we sent
+        Conflict { app: String, instance: String } {
+            display("Eureka has a conflicting copy of instance {} of app {}", instance, app)
+        }
+        ServerError { operation: &'static str, method: Method, url: String, status: StatusCode, body: String } {
+            display("{} failed with {} from {} {}: {}", operation, status, method, url, body)
+        }
+        /// Any other unexpected response status
+        Request { operation: &'static str, method: Method, url: String, status: StatusCode, body: String } {
+            display("{} failed with {} from {} {}: {}", operation, status, method, url, body)
+        }
+        /// A configuration value is invalid, `key` names the offending setting
+        InvalidConfig { key: String, reason: String } {
+            display("Invalid value for {}: {}", key, reason)
+        }
+        /// The instance advertises neither an enabled port nor a secure port
+        NoUsablePort { app: String, instance: String } {
+            display("Instance {} of app {} advertises no usable port", instance, app)
+        }
+        /// Requests to other apps need the registry, which is disabled,
+        /// and there are no static instances for the app
+        RegistryDisabled {
+            display("Fetching the registry is disabled, so apps cannot be looked up")
+        }
+        /// The registry hasn't been fetched within `EurekaConfig::max_registry_age`
+        RegistryExpired { last_success: Option<SystemTime> } {
+            display("The registry has not been fetched recently enough to look up apps")
+        }
+        NoInstancesAvailable { app: String } {
+            display("No instances of app {} are available", app)
         }
-        Request(status: StatusCode) {
-            description(status.canonical_reason().unwrap_or("Unknown Status Code"))
+        /// Every attempt failed, `last` is the error from the final attempt
+        AllServersFailed { attempts: usize, last: Box<EurekaError> } {
+            display("All {} attempts failed, last error: {}", attempts, last)
+            source(&**last)
         }
-        UnexpectedState(description: String) {
-            description(description)
+        Serialize(err: Box<dyn StdError + Send + Sync>) {
+            display("Failed to serialize request body: {}", err)
+            source(&**err)
+        }
+        Deserialize { source: Box<dyn StdError + Send + Sync>, body_excerpt: String } {
+            display("Failed to deserialize response body ({}): {}", source, body_excerpt)
+            source(&**source)
         }
-        ParseError(description: String) {}
+    }
+}
+
+impl From<ReqwestError> for EurekaError {
+    fn from(err: ReqwestError) -> Self {
+        if err.is_timeout() {
+            EurekaError::Timeout(err)
+        } else {
+            EurekaError::Network(err)
+        }
+    }
+}
+
+impl EurekaError {
+    fn serialize<E: Into<Box<dyn StdError + Send + Sync>>>(err: E) -> Self {
+        EurekaError::Serialize(err.into())
+    }
+
+    /// Builds a `Deserialize` error, keeping the start of the offending body for context
+    fn deserialize<E: Into<Box<dyn StdError + Send + Sync>>>(err: E, body: &str) -> Self {
+        EurekaError::Deserialize {
+            source: err.into(),
+            body_excerpt: excerpt(body),
+        }
+    }
+
+    /// Maps an unexpected response status to the matching error
+    fn from_status(
+        operation: &'static str,
+        method: Method,
+        app: Option<&str>,
+        instance: Option<&str>,
+        mut resp: Response,
+    ) -> Self {
+        let status = resp.status();
+        match (status, app) {
+            (StatusCode::NOT_FOUND, _) if app.is_some() || instance.is_some() => {
+                EurekaError::NotFound {
+                    app: app.map(|app| app.to_string()),
+                    instance: instance.map(|instance| instance.to_string()),
+                }
+            }
+            (StatusCode::CONFLICT, Some(app)) => EurekaError::Conflict {
+                app: app.to_string(),
+                instance: instance.unwrap_or_default().to_string(),
+            },
+            _ => {
+                let url = resp.url().to_string();
+                let body = resp.text().map(|body| excerpt(&body)).unwrap_or_default();
+                if status.is_server_error() {
+                    EurekaError::ServerError {
+                        operation,
+                        method,
+                        url,
+                        status,
+                        body,
+                    }
+                } else {
+                    EurekaError::Request {
+                        operation,
+                        method,
+                        url,
+                        status,
+                        body,
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn excerpt(body: &str) -> String {
+    const MAX_LEN: usize = 256;
+    match body.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_string(),
     }
 }
 
 #[derive(Debug)]
 pub struct EurekaClient {
-    base_url: String,
     config: BaseConfig,
-    client: ReqwestClient,
+    /// Shared by the registry, instance and app clients, so their connections are reused
+    http: HttpClients,
     registry: RegistryClient,
-    instance: Option<InstanceClient>,
+    instance: InstanceClient,
+    /// Start with `EurekaConfig::fetch_registry`, and can be enabled at runtime
+    registry_enabled: AtomicBool,
+    /// Start with `EurekaConfig::register_with_eureka`, and can be enabled at runtime
+    registration_enabled: AtomicBool,
+    started: AtomicBool,
+    breakers: Arc<CircuitBreakers>,
 }
 
 impl EurekaClient {
-    pub fn new(config: BaseConfig) -> Self {
-        let base_url = {
-            let ssl = config.eureka.ssl;
-            let protocol = if ssl { "https" } else { "http" };
-            let host = &config.eureka.host;
-            let port = config.eureka.port;
-            let service_path = &config.eureka.service_path;
-            format!("{}://{}:{}{}", protocol, host, port, service_path)
-        };
-        EurekaClient {
-            base_url: base_url.clone(),
-            client: ReqwestClient::new(),
-            registry: RegistryClient::new(base_url.clone()),
-            instance: if config.eureka.register_with_eureka {
-                Some(InstanceClient::new(base_url, config.instance.clone()))
-            } else {
-                None
-            },
-            config,
-        }
+    /// Fails if the HTTP clients cannot be built, e.g. because of invalid TLS settings
+    pub fn new(config: BaseConfig) -> Result<Self, EurekaError> {
+        Self::builder().config(config).build()
     }
 
+    /// Starts building a client, for settings beyond those in `BaseConfig`
+    pub fn builder() -> EurekaClientBuilder {
+        EurekaClientBuilder::new()
+    }
+
+    /// Starts fetching the registry and registers with eureka, as enabled.
+    /// This blocks until the instance is registered.
     pub fn start(&self) {
-        self.registry.start();
-        if let Some(ref instance) = self.instance {
-            instance.start();
+        self.started.store(true, Ordering::SeqCst);
+        if self.registry_enabled.load(Ordering::SeqCst) {
+            self.registry.start();
+        }
+        if self.registration_enabled.load(Ordering::SeqCst) {
+            self.start_registration();
         }
     }
 
+    /// Starts fetching the registry, if it was disabled by `EurekaConfig::fetch_registry`.
+    /// This takes effect once the client is started.
+    pub fn enable_registry(&self) {
+        self.registry_enabled.store(true, Ordering::SeqCst);
+        if self.started.load(Ordering::SeqCst) {
+            self.registry.start();
+        }
+    }
+
+    /// Registers with eureka, if it was disabled by `EurekaConfig::register_with_eureka`.
+    /// This takes effect once the client is started, and blocks until the instance is registered.
+    ///
+    /// Fails if the instance is missing an app name, IP address or enabled port.
+    pub fn enable_registration(&self) -> Result<(), EurekaError> {
+        validate_instance(&self.config.instance)?;
+        self.registration_enabled.store(true, Ordering::SeqCst);
+        if self.started.load(Ordering::SeqCst) {
+            self.start_registration();
+        }
+        Ok(())
+    }
+
+    fn start_registration(&self) {
+        self.instance.load_aws_metadata();
+        self.instance.start();
+    }
+
+    /// Starts building a request to another app in this eureka cluster.
+    ///
+    /// Any body type is supported: raw bytes, JSON, forms, multipart and streams.
+    pub fn request(&self, app: &str) -> AppRequest<'_> {
+        AppRequest::new(self, app)
+    }
+
     /// Sends a request to another app in this eureka cluster, and returns the response.
     ///
-    /// This method assumes that your services all communicate using JSON.
-    /// Future methods may be added to allow other request body types.
+    /// This method sends `body` as JSON, use `request` for other body types.
     ///
     /// `Accept: "application/json"` is preset on all requests by this method.
     /// You can add additional headers such as `Authorization` using the `headers` parameter.
+    ///
+    /// Failed requests are retried according to `EurekaConfig::request_retry`,
+    /// moving on to other instances of the app as needed.
     pub fn make_request<V: Serialize>(
         &self,
         app: &str,
         path: &str,
         method: Method,
         body: &V,
-        mut headers: HeaderMap,
+        headers: HeaderMap,
     ) -> Result<Response, EurekaError> {
-        let instance = self.registry.get_instance_by_app_name(app);
-        if let Some(instance) = instance {
-            let ssl = self.config.eureka.ssl;
-            let protocol = if ssl { "https" } else { "http" };
-            let host = instance.ip_addr;
-            let port = if ssl && instance.secure_port.value().is_some() {
-                instance.secure_port.value().unwrap()
-            } else {
-                instance.port.and_then(|port| port.value()).unwrap_or(8080)
-            };
-            headers.insert("Accept", "application/json".parse().unwrap());
-            self.client
-                .request(
-                    method,
-                    &format!(
-                        "{}://{}:{}/{}",
-                        protocol,
-                        host,
-                        port,
-                        path.trim_left_matches('/')
-                    ),
-                ).headers(headers)
-                .json(body)
-                .send()
-                .map_err(EurekaError::Network)
-        } else {
-            Err(EurekaError::UnexpectedState(format!(
-                "Could not find app {}",
-                app
-            )))
+        self.json_request(app, path, method, body, headers).send()
+    }
+
+    /// Same as `make_request`, but deserializes a successful response from JSON as `Resp`.
+    ///
+    /// For an error status, the body is decoded as `E` when possible and returned in the error.
+    pub fn make_request_json<Req, Resp, E>(
+        &self,
+        app: &str,
+        path: &str,
+        method: Method,
+        body: &Req,
+        headers: HeaderMap,
+    ) -> Result<Resp, ResponseError<E>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        self.json_request(app, path, method, body, headers)
+            .send_json()
+    }
+
+    /// Same as `make_request`, but with `timeout` in place of `EurekaConfig::timeouts.application`.
+    ///
+    /// A client passed to `EurekaClientBuilder::http_client` can't be given another timeout,
+    /// so `timeout` is ignored with a warning in that case.
+    pub fn make_request_with_timeout<V: Serialize>(
+        &self,
+        app: &str,
+        path: &str,
+        method: Method,
+        body: &V,
+        headers: HeaderMap,
+        timeout: TimeoutConfig,
+    ) -> Result<Response, EurekaError> {
+        self.json_request(app, path, method, body, headers)
+            .timeout(timeout)
+            .send()
+    }
+
+    fn json_request<V: Serialize>(
+        &self,
+        app: &str,
+        path: &str,
+        method: Method,
+        body: &V,
+        mut headers: HeaderMap,
+    ) -> AppRequest<'_> {
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        self.request(app)
+            .method(method, path)
+            .headers(headers)
+            .json(body)
+    }
+
+    /// The client for requests to other apps with the given timeout, or the default one
+    fn client_for_timeout(
+        &self,
+        timeout: Option<TimeoutConfig>,
+    ) -> Result<ReqwestClient, EurekaError> {
+        if timeout.is_some() && self.http.is_custom() {
+            warn!("Ignoring the timeout for this request, the custom HTTP client's timeouts apply");
         }
+        self.http
+            .get(timeout.unwrap_or(self.config.eureka.timeouts.application))
+    }
+
+    /// Whether apps are being looked up in a registry snapshot from disk,
+    /// because the registry has not been fetched from Eureka yet
+    pub fn is_registry_stale(&self) -> bool {
+        self.registry.is_stale()
+    }
+
+    /// Returns the outcome of recent registry fetches, including when and from where
+    /// the registry was last fetched
+    pub fn registry_status(&self) -> RegistryStatus {
+        self.registry.status()
+    }
+
+    /// Calls `listener` on the registry's thread for every change to instances found when fetching the registry,
+    /// such as an instance restarting
+    pub fn subscribe<F>(&self, listener: F)
+    where
+        F: Fn(&RegistryEvent) + Send + Sync + 'static,
+    {
+        self.registry.subscribe(listener);
+    }
+
+    /// Returns the outcome of recent heartbeats
+    pub fn heartbeat_status(&self) -> HeartbeatStatus {
+        self.instance.heartbeat_status()
+    }
+
+    /// Returns the state of the circuit breaker for every instance that has received requests,
+    /// keyed by instance ID
+    pub fn circuit_breakers(&self) -> HashMap<String, BreakerSnapshot> {
+        self.breakers.snapshot()
     }
 }
 
@@ -189,3 +533,57 @@ fn path_segment_encode(value: &str) -> String {
 fn query_encode(value: &str) -> String {
     percent_encoding::utf8_percent_encode(value, percent_encoding::QUERY_ENCODE_SET).to_string()
 }
+
+fn current_time_millis() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() * 1000 + u64::from(now.subsec_millis()))
+        .unwrap_or(0)
+}
+
+fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u32(now.subsec_nanos());
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config: BaseConfig = serde_json::from_str(
+            r#"{"eureka": {"host": "eureka.local", "requestRetry": {"maxRetriesSameInstance": 2}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.eureka.host, "eureka.local");
+        assert_eq!(config.eureka.port, 8761);
+        assert_eq!(config.eureka.request_retry.max_retries_same_instance, 2);
+        assert_eq!(
+            config.eureka.request_retry.max_retries_next_instances,
+            RetryPolicy::default().max_retries_next_instances
+        );
+        assert_eq!(config.eureka.wire_format, WireFormat::Json);
+    }
+
+    #[test]
+    fn not_found_without_app() {
+        let error = EurekaError::NotFound {
+            app: None,
+            instance: Some("10.0.0.11:ORDER-SERVICE:8080".to_string()),
+        };
+        assert_eq!(
+            error.to_string(),
+            "Instance 10.0.0.11:ORDER-SERVICE:8080 not found"
+        );
+    }
+}
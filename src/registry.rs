@@ -1,66 +1,284 @@
 use std::collections::HashMap;
+use std::fmt::{Debug, Error as FmtError, Formatter};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use itertools::Itertools;
+use reqwest::Url;
 
-use rest::structures::Instance;
+use rest::structures::{Instance, PortData, StatusType};
 use rest::EurekaRestClient;
+use snapshot::{self, RegistryCacheConfig};
+use {current_time_millis, random_u64, EurekaConfig, EurekaError};
+
+/// Fixed instances for apps, for local development or for when Eureka is unavailable
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StaticInstancesConfig {
+    /// Addresses of each app's instances, as `host:port`, or `https://host:port` for a secure port
+    pub apps: HashMap<String, Vec<String>>,
+    /// Serve these alongside the instances from Eureka,
+    /// rather than only when Eureka is disabled, down or doesn't know the app
+    pub merge: bool,
+}
+
+/// The outcome of recent registry fetches, from `EurekaClient::registry_status`
+#[derive(Debug, Clone, Default)]
+pub struct RegistryStatus {
+    pub consecutive_failures: usize,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    /// The Eureka server the cached registry was last fetched from
+    pub server: Option<String>,
+}
+
+impl RegistryStatus {
+    /// Time since the last successful fetch, or `None` if there hasn't been one
+    pub fn age(&self) -> Option<Duration> {
+        self.last_success
+            .map(|time| time.elapsed().unwrap_or_default())
+    }
+}
+
+/// A change to an instance, found by comparing a fetch of the registry with the previous one
+#[derive(Debug, Clone)]
+pub enum RegistryEvent {
+    /// An instance that wasn't in the registry before, including every instance on the first fetch
+    Registered(Instance),
+    /// An instance registered again under the same ID, as when its process restarts
+    Restarted(Instance),
+    /// Eureka changed an instance, as when its status changes
+    Updated(Instance),
+    /// An instance is no longer in the registry
+    Removed(Instance),
+}
+
+type Listener = Box<dyn Fn(&RegistryEvent) + Send + Sync>;
+
+#[derive(Default)]
+struct Listeners(RwLock<Vec<Listener>>);
+
+impl Debug for Listeners {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "Listeners({})", self.0.read().unwrap().len())
+    }
+}
 
 #[derive(Debug)]
 pub struct RegistryClient {
+    inner: Arc<Registry>,
+}
+
+/// State shared with the thread that fetches the registry
+#[derive(Debug)]
+struct Registry {
     client: Arc<EurekaRestClient>,
-    app_cache: Arc<RwLock<HashMap<String, Vec<Instance>>>>,
-    is_running: Arc<AtomicBool>,
+    app_cache: RwLock<HashMap<String, Vec<Instance>>>,
+    is_running: AtomicBool,
+    /// Set while the cache holds a snapshot from disk rather than a live fetch
+    stale: AtomicBool,
+    status: Mutex<RegistryStatus>,
+    /// Lookups fail once the registry is older than this, in milliseconds, unless it is 0
+    max_age: usize,
+    created: SystemTime,
+    static_instances: HashMap<String, Vec<Instance>>,
+    merge_static_instances: bool,
+    listeners: Listeners,
+    /// Whether instances' metadata maps are kept in the cache
+    fetch_metadata: bool,
+    /// Whether only instances that are `UP` are kept in the cache
+    filter_up_instances: bool,
+    fetch_interval: Duration,
+    cache: RegistryCacheConfig,
 }
 
 impl RegistryClient {
-    pub fn new(base_url: String) -> Self {
+    /// Loads the registry snapshot, if one is configured
+    pub fn new(
+        client: Arc<EurekaRestClient>,
+        config: &EurekaConfig,
+        static_instances: HashMap<String, Vec<Instance>>,
+    ) -> Self {
+        let snapshot = snapshot::load(&config.registry_cache);
+        let registry = Registry {
+            client,
+            stale: AtomicBool::new(snapshot.is_some()),
+            status: Mutex::new(RegistryStatus::default()),
+            max_age: config.max_registry_age,
+            created: SystemTime::now(),
+            listeners: Listeners::default(),
+            static_instances,
+            merge_static_instances: config.static_instances.merge,
+            app_cache: RwLock::new(snapshot.map(group_instances_by_app).unwrap_or_default()),
+            is_running: AtomicBool::new(false),
+            fetch_metadata: config.fetch_metadata,
+            filter_up_instances: config.filter_up_instances,
+            fetch_interval: Duration::from_millis(config.registry_fetch_interval as u64),
+            cache: config.registry_cache.clone(),
+        };
         RegistryClient {
-            client: Arc::new(EurekaRestClient::new(base_url)),
-            app_cache: Arc::new(RwLock::new(HashMap::new())),
-            is_running: Arc::new(AtomicBool::new(false)),
+            inner: Arc::new(registry),
         }
     }
 
     pub fn start(&self) {
-        self.is_running.store(true, Ordering::Relaxed);
+        if self.inner.is_running.swap(true, Ordering::SeqCst) {
+            return;
+        }
 
-        let is_running = Arc::clone(&self.is_running);
-        let client = Arc::clone(&self.client);
-        let app_cache = Arc::clone(&self.app_cache);
+        let registry = Arc::clone(&self.inner);
         thread::spawn(move || {
-            while is_running.load(Ordering::Relaxed) {
-                let resp = client.get_all_instances();
-                match resp {
-                    Ok(instances) => {
-                        *app_cache.write().unwrap() = group_instances_by_app(instances);
-                    }
-                    Err(e) => {
-                        error!("Failed to fetch registry: {}", e);
-                    }
-                };
-                thread::sleep(Duration::from_secs(30));
+            while registry.is_running.load(Ordering::Relaxed) {
+                registry.fetch();
+                thread::sleep(registry.fetch_interval);
             }
         });
     }
 
-    pub fn get_instance_by_app_name(&self, app: &str) -> Option<Instance> {
-        // Clone the result to avoid holding onto a lock on the app cache indefinitely
-        self.app_cache
-            .read()
-            .unwrap()
+    /// Looks up the instances of `app`, falling back on or adding static instances as configured.
+    ///
+    /// Fails if `EurekaConfig::max_registry_age` has passed since the registry was last fetched,
+    /// unless there are static instances for the app.
+    pub fn get_instances_by_app_name(&self, app: &str) -> Result<Vec<Instance>, EurekaError> {
+        let expired = self.inner.is_expired();
+        let mut instances = if expired {
+            Vec::new()
+        } else {
+            // Clone the result to avoid holding onto a lock on the app cache indefinitely
+            self.inner
+                .app_cache
+                .read()
+                .unwrap()
+                .get(app)
+                .cloned()
+                .unwrap_or_default()
+        };
+        let static_instances = self.get_static_instances(app);
+        if self.inner.merge_static_instances {
+            for instance in static_instances {
+                if !instances.iter().any(|live| live.id() == instance.id()) {
+                    instances.push(instance);
+                }
+            }
+        } else if (instances.is_empty() || !self.inner.is_healthy()) && !static_instances.is_empty()
+        {
+            instances = static_instances;
+        }
+        if instances.is_empty() && expired {
+            return Err(EurekaError::RegistryExpired {
+                last_success: self.status().last_success,
+            });
+        }
+        Ok(instances)
+    }
+
+    /// The instances configured in `EurekaConfig::static_instances` for `app`
+    pub fn get_static_instances(&self, app: &str) -> Vec<Instance> {
+        self.inner
+            .static_instances
             .get(app)
-            .and_then(|instances| instances.get(0))
             .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether the cache still holds a snapshot from disk, because no live fetch has succeeded yet
+    pub fn is_stale(&self) -> bool {
+        self.inner.stale.load(Ordering::Relaxed)
+    }
+
+    /// Calls `listener` with the changes found on every fetch of the registry
+    pub fn subscribe<F>(&self, listener: F)
+    where
+        F: Fn(&RegistryEvent) + Send + Sync + 'static,
+    {
+        self.inner
+            .listeners
+            .0
+            .write()
+            .unwrap()
+            .push(Box::new(listener));
+    }
+
+    pub fn status(&self) -> RegistryStatus {
+        self.inner.status.lock().unwrap().clone()
+    }
+}
+
+impl Registry {
+    /// Whether the last fetch succeeded
+    fn is_healthy(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        status.last_success.is_some() && status.consecutive_failures == 0
+    }
+
+    fn is_expired(&self) -> bool {
+        if self.max_age == 0 {
+            return false;
+        }
+        let since = self
+            .status
+            .lock()
+            .unwrap()
+            .last_success
+            .unwrap_or(self.created);
+        since.elapsed().unwrap_or_default() > Duration::from_millis(self.max_age as u64)
+    }
+
+    fn fetch(&self) {
+        let resp = self.client.get_all_instances();
+        match resp {
+            Ok(mut instances) => {
+                if self.filter_up_instances {
+                    instances.retain(|instance| instance.status == StatusType::Up);
+                }
+                if !self.fetch_metadata {
+                    for instance in &mut instances {
+                        instance.metadata = None;
+                    }
+                }
+                snapshot::save(&self.cache, &instances);
+                let apps = group_instances_by_app(instances);
+                let events = {
+                    let mut cache = self.app_cache.write().unwrap();
+                    let events = registry_events(&cache, &apps);
+                    *cache = apps;
+                    events
+                };
+                self.notify(&events);
+                self.stale.store(false, Ordering::Relaxed);
+                let mut status = self.status.lock().unwrap();
+                status.consecutive_failures = 0;
+                status.last_success = Some(SystemTime::now());
+                status.server = Some(self.client.base_url().to_string());
+            }
+            Err(e) => {
+                let mut status = self.status.lock().unwrap();
+                status.consecutive_failures += 1;
+                status.last_failure = Some(SystemTime::now());
+                error!(
+                    "Failed to fetch registry ({} consecutive failures): {}",
+                    status.consecutive_failures, e
+                );
+            }
+        };
+    }
+
+    fn notify(&self, events: &[RegistryEvent]) {
+        let listeners = self.listeners.0.read().unwrap();
+        for event in events {
+            debug!("Registry event: {:?}", event);
+            for listener in listeners.iter() {
+                listener(event);
+            }
+        }
     }
 }
 
 impl Drop for RegistryClient {
     fn drop(&mut self) {
-        self.is_running.store(false, Ordering::Relaxed);
+        self.inner.is_running.store(false, Ordering::Relaxed);
     }
 }
 
@@ -72,3 +290,544 @@ fn group_instances_by_app(instances: Vec<Instance>) -> HashMap<String, Vec<Insta
         .map(|(k, g)| (k, g.collect()))
         .collect()
 }
+
+/// Compares the instances of each app in `old` and `new`
+fn registry_events(
+    old: &HashMap<String, Vec<Instance>>,
+    new: &HashMap<String, Vec<Instance>>,
+) -> Vec<RegistryEvent> {
+    let find = |apps: &HashMap<String, Vec<Instance>>, instance: &Instance| {
+        apps.get(&instance.app)
+            .and_then(|instances| instances.iter().find(|other| other.id() == instance.id()))
+            .cloned()
+    };
+    let registration = |instance: &Instance| {
+        instance
+            .lease_info
+            .and_then(|lease| lease.registration_timestamp)
+    };
+    let mut events = Vec::new();
+    for instance in Iterator::flatten(new.values()) {
+        let event = match find(old, instance) {
+            None => RegistryEvent::Registered(instance.clone()),
+            Some(ref previous) if registration(previous) != registration(instance) => {
+                RegistryEvent::Restarted(instance.clone())
+            }
+            Some(ref previous)
+                if previous.last_updated_timestamp != instance.last_updated_timestamp =>
+            {
+                RegistryEvent::Updated(instance.clone())
+            }
+            Some(_) => continue,
+        };
+        events.push(event);
+    }
+    for instance in Iterator::flatten(old.values()) {
+        if find(new, instance).is_none() {
+            events.push(RegistryEvent::Removed(instance.clone()));
+        }
+    }
+    events
+}
+
+/// Orders instances randomly, so that requests are spread across them,
+/// with the first instance picked in proportion to its `slow_start_weight`
+pub fn load_balance(instances: Vec<Instance>, slow_start_window: usize) -> Vec<Instance> {
+    let now = current_time_millis();
+    // Weighted sampling without replacement, as described by Efraimidis and Spirakis.
+    // Comparing the logarithms of the keys `random^(1/weight)` keeps them from underflowing
+    // to 0 for small weights. Instances without any weight come after all others, shuffled.
+    let mut keyed: Vec<(bool, f64, Instance)> = instances
+        .into_iter()
+        .map(|instance| {
+            let weight = slow_start_weight(&instance, slow_start_window, now);
+            let random = random_u64() as f64 / u64::MAX as f64;
+            if weight > 0.0 {
+                (true, random.ln() / weight, instance)
+            } else {
+                (false, random.ln(), instance)
+            }
+        })
+        .collect();
+    keyed.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.total_cmp(&a.1)));
+    keyed.into_iter().map(|(_, _, instance)| instance).collect()
+}
+
+/// Ramps up from 0 to 1 over the `window` milliseconds after the instance registered,
+/// and is 1 for instances with no registration time or when `window` is 0
+fn slow_start_weight(instance: &Instance, window: usize, now: u64) -> f64 {
+    let registered = instance
+        .lease_info
+        .and_then(|lease| lease.registration_timestamp);
+    match registered {
+        Some(registered) if window > 0 => {
+            let age = now.saturating_sub(registered);
+            (age as f64 / window as f64).min(1.0)
+        }
+        _ => 1.0,
+    }
+}
+
+/// Turns the addresses in `config` into instances, failing on any that can't be parsed
+pub fn parse_static_instances(
+    config: &StaticInstancesConfig,
+) -> Result<HashMap<String, Vec<Instance>>, EurekaError> {
+    config
+        .apps
+        .iter()
+        .map(|(app, addresses)| {
+            let instances = addresses
+                .iter()
+                .map(|address| static_instance(app, address))
+                .collect::<Result<_, _>>()?;
+            Ok((app.clone(), instances))
+        })
+        .collect()
+}
+
+fn static_instance(app: &str, address: &str) -> Result<Instance, EurekaError> {
+    let invalid = |reason: String| EurekaError::InvalidConfig {
+        key: format!("eureka.staticInstances.apps.{}", app),
+        reason: format!("{}: {}", address, reason),
+    };
+    let url = if address.contains("://") {
+        Url::parse(address)
+    } else {
+        Url::parse(&format!("http://{}", address))
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid(format!("unsupported scheme {}", url.scheme())));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("no host".to_string()))?
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| invalid("no port".to_string()))?;
+    let secure = url.scheme() == "https";
+    let base_url = format!("{}://{}:{}", url.scheme(), host, port);
+    Ok(Instance {
+        instance_id: Some(format!("{}:{}", host, port)),
+        host_name: host.clone(),
+        app: app.to_string(),
+        ip_addr: host,
+        vip_address: app.to_string(),
+        secure_vip_address: app.to_string(),
+        status: StatusType::Up,
+        port: Some(PortData::new(port, !secure)),
+        secure_port: PortData::new(port, secure),
+        home_page_url: format!("{}/", base_url),
+        ..Instance::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rest::structures::LeaseInfo;
+    use serde_json;
+    use std::env;
+    use test_server::TestServer;
+
+    fn instance(id: &str, registered: Option<u64>) -> Instance {
+        Instance {
+            instance_id: Some(id.to_string()),
+            app: "APP".to_string(),
+            lease_info: Some(LeaseInfo {
+                registration_timestamp: registered,
+                ..LeaseInfo::default()
+            }),
+            ..Instance::default()
+        }
+    }
+
+    fn up(id: &str) -> Instance {
+        Instance {
+            status: StatusType::Up,
+            ..instance(id, None)
+        }
+    }
+
+    /// A Eureka server answering registry fetches with the status and body in `reply`,
+    /// which can be changed between fetches
+    fn eureka_server() -> (TestServer, Arc<Mutex<(u16, String)>>) {
+        let reply = Arc::new(Mutex::new((500, String::new())));
+        let current = Arc::clone(&reply);
+        let server = TestServer::new(move |_| current.lock().unwrap().clone());
+        (server, reply)
+    }
+
+    fn applications(instances: &[Instance]) -> (u16, String) {
+        let instances = serde_json::to_string(instances).unwrap();
+        let body = format!(
+            r#"{{"applications":{{"application":[{{"name":"APP","instance":{}}}]}}}}"#,
+            instances
+        );
+        (200, body)
+    }
+
+    fn registry(
+        server: &TestServer,
+        config: &EurekaConfig,
+        static_instances: &StaticInstancesConfig,
+    ) -> RegistryClient {
+        let client = Arc::new(EurekaRestClient::new(server.url(), config).unwrap());
+        let static_instances = parse_static_instances(static_instances).unwrap();
+        RegistryClient::new(client, config, static_instances)
+    }
+
+    fn ids(instances: Result<Vec<Instance>, EurekaError>) -> Vec<String> {
+        instances
+            .unwrap()
+            .iter()
+            .map(|instance| instance.id().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_is_stale_until_a_live_fetch_succeeds() {
+        let path = env::temp_dir().join(format!("eureka-registry-{}", std::process::id()));
+        let cache = RegistryCacheConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            ..RegistryCacheConfig::default()
+        };
+        snapshot::save(&cache, &[up("cached")]);
+        let config = EurekaConfig {
+            registry_cache: cache.clone(),
+            ..EurekaConfig::default()
+        };
+        let (server, reply) = eureka_server();
+        let registry = registry(&server, &config, &StaticInstancesConfig::default());
+        assert!(registry.is_stale());
+        assert_eq!(
+            ids(registry.get_instances_by_app_name("APP")),
+            vec!["cached"]
+        );
+
+        registry.inner.fetch();
+        assert!(registry.is_stale());
+
+        *reply.lock().unwrap() = applications(&[up("live")]);
+        registry.inner.fetch();
+        assert!(!registry.is_stale());
+        assert_eq!(ids(registry.get_instances_by_app_name("APP")), vec!["live"]);
+        assert_eq!(snapshot::load(&cache).unwrap()[0].id(), "live");
+    }
+
+    fn static_apps(addresses: &[&str]) -> StaticInstancesConfig {
+        let mut config = StaticInstancesConfig::default();
+        config.apps.insert(
+            "APP".to_string(),
+            addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+        );
+        config
+    }
+
+    #[test]
+    fn parses_static_instance_addresses() {
+        let apps = parse_static_instances(&static_apps(&[
+            "10.0.0.1:8080",
+            "app.internal",
+            "https://secure.internal:8443",
+            "https://secure.internal",
+        ]))
+        .unwrap();
+        let instances = &apps["APP"];
+        let ports: Vec<_> = instances
+            .iter()
+            .map(|instance| {
+                let port = instance.port.as_ref().and_then(PortData::value);
+                (instance.id(), port, instance.secure_port.value())
+            })
+            .collect();
+        assert_eq!(
+            ports,
+            vec![
+                ("10.0.0.1:8080", Some(8080), None),
+                ("app.internal:80", Some(80), None),
+                ("secure.internal:8443", None, Some(8443)),
+                ("secure.internal:443", None, Some(443)),
+            ]
+        );
+        assert_eq!(instances[0].home_page_url, "http://10.0.0.1:8080/");
+        assert_eq!(instances[2].home_page_url, "https://secure.internal:8443/");
+        assert_eq!(instances[0].status, StatusType::Up);
+    }
+
+    #[test]
+    fn rejects_invalid_static_instance_addresses() {
+        for address in &["10.0.0.1:http", "http://", "ftp://files.internal"] {
+            match parse_static_instances(&static_apps(&[address])) {
+                Err(EurekaError::InvalidConfig { key, reason }) => {
+                    assert_eq!(key, "eureka.staticInstances.apps.APP");
+                    assert!(reason.starts_with(address), "{}", reason);
+                }
+                other => panic!("{} was accepted: {:?}", address, other),
+            }
+        }
+    }
+
+    #[test]
+    fn static_instances_stand_in_for_missing_or_failing_registry() {
+        let (server, reply) = eureka_server();
+        let static_instances = static_apps(&["10.0.0.1:8080"]);
+        let registry = registry(&server, &EurekaConfig::default(), &static_instances);
+        assert_eq!(
+            ids(registry.get_instances_by_app_name("APP")),
+            vec!["10.0.0.1:8080"]
+        );
+
+        *reply.lock().unwrap() = applications(&[up("live")]);
+        registry.inner.fetch();
+        assert_eq!(ids(registry.get_instances_by_app_name("APP")), vec!["live"]);
+
+        *reply.lock().unwrap() = (500, String::new());
+        registry.inner.fetch();
+        assert_eq!(
+            ids(registry.get_instances_by_app_name("APP")),
+            vec!["10.0.0.1:8080"]
+        );
+    }
+
+    #[test]
+    fn static_instances_are_merged_with_the_registry() {
+        let (server, reply) = eureka_server();
+        let mut static_instances = static_apps(&["10.0.0.1:8080", "10.0.0.2:8080"]);
+        static_instances.merge = true;
+        let config = EurekaConfig {
+            static_instances: static_instances.clone(),
+            ..EurekaConfig::default()
+        };
+        let registry = registry(&server, &config, &static_instances);
+        *reply.lock().unwrap() = applications(&[up("live"), up("10.0.0.2:8080")]);
+        registry.inner.fetch();
+
+        assert_eq!(
+            ids(registry.get_instances_by_app_name("APP")),
+            vec!["live", "10.0.0.2:8080", "10.0.0.1:8080"]
+        );
+    }
+
+    fn expiring(max_registry_age: usize, static_instances: &StaticInstancesConfig) -> EurekaConfig {
+        EurekaConfig {
+            max_registry_age,
+            static_instances: static_instances.clone(),
+            ..EurekaConfig::default()
+        }
+    }
+
+    #[test]
+    fn registry_expires_after_max_age_without_a_fetch() {
+        let (server, reply) = eureka_server();
+        let none = StaticInstancesConfig::default();
+        let registry = registry(&server, &expiring(200, &none), &none);
+        *reply.lock().unwrap() = applications(&[up("live")]);
+        registry.inner.fetch();
+        assert!(!registry.inner.is_expired());
+        assert_eq!(ids(registry.get_instances_by_app_name("APP")), vec!["live"]);
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(registry.inner.is_expired());
+        match registry.get_instances_by_app_name("APP") {
+            Err(EurekaError::RegistryExpired { last_success }) => {
+                assert_eq!(last_success, registry.status().last_success);
+                assert!(last_success.is_some());
+            }
+            other => panic!("expected RegistryExpired, got {:?}", other),
+        }
+
+        registry.inner.fetch();
+        assert!(!registry.inner.is_expired());
+    }
+
+    #[test]
+    fn registry_without_a_fetch_expires_from_creation() {
+        let (server, _) = eureka_server();
+        let none = StaticInstancesConfig::default();
+        let registry = registry(&server, &expiring(200, &none), &none);
+        assert!(!registry.inner.is_expired());
+        thread::sleep(Duration::from_millis(300));
+        assert!(matches!(
+            registry.get_instances_by_app_name("APP"),
+            Err(EurekaError::RegistryExpired { last_success: None })
+        ));
+    }
+
+    #[test]
+    fn registry_never_expires_without_max_age() {
+        let (server, _) = eureka_server();
+        let none = StaticInstancesConfig::default();
+        let registry = registry(&server, &expiring(0, &none), &none);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!registry.inner.is_expired());
+        assert!(registry
+            .get_instances_by_app_name("APP")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn static_instances_outlive_an_expired_registry() {
+        for &merge in &[false, true] {
+            let (server, reply) = eureka_server();
+            let mut static_instances = static_apps(&["10.0.0.1:8080"]);
+            static_instances.merge = merge;
+            let config = expiring(200, &static_instances);
+            let registry = registry(&server, &config, &static_instances);
+            *reply.lock().unwrap() = applications(&[up("live")]);
+            registry.inner.fetch();
+
+            thread::sleep(Duration::from_millis(300));
+            assert_eq!(
+                ids(registry.get_instances_by_app_name("APP")),
+                vec!["10.0.0.1:8080"]
+            );
+        }
+    }
+
+    #[test]
+    fn status_counts_failures_until_a_fetch_succeeds() {
+        let (server, reply) = eureka_server();
+        let none = StaticInstancesConfig::default();
+        let registry = registry(&server, &EurekaConfig::default(), &none);
+        registry.inner.fetch();
+        registry.inner.fetch();
+        let status = registry.status();
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.last_failure.is_some());
+        assert!(status.last_success.is_none());
+        assert!(status.age().is_none());
+        assert!(status.server.is_none());
+
+        *reply.lock().unwrap() = applications(&[up("live")]);
+        registry.inner.fetch();
+        let status = registry.status();
+        assert_eq!(status.consecutive_failures, 0);
+        assert!(status.last_success.is_some());
+        assert!(status.age().is_some());
+        assert_eq!(status.server, Some(server.url()));
+
+        *reply.lock().unwrap() = (500, String::new());
+        registry.inner.fetch();
+        let after_failure = registry.status();
+        assert_eq!(after_failure.consecutive_failures, 1);
+        assert_eq!(after_failure.last_success, status.last_success);
+    }
+
+    /// How often each instance comes first out of `load_balance`
+    fn first_picks(instances: &[Instance], window: usize) -> HashMap<String, usize> {
+        let mut picks = HashMap::new();
+        for _ in 0..4_000 {
+            let first = load_balance(instances.to_vec(), window).remove(0);
+            *picks.entry(first.id().to_string()).or_insert(0) += 1;
+        }
+        picks
+    }
+
+    #[test]
+    fn slow_start_weight_ramps_up_over_the_window() {
+        let now = 1_000_000;
+        let fresh = instance("a", Some(now));
+        let halfway = instance("b", Some(now - 5_000));
+        let old = instance("c", Some(now - 60_000));
+        let unknown = instance("d", None);
+        assert_eq!(slow_start_weight(&fresh, 10_000, now), 0.0);
+        assert_eq!(slow_start_weight(&halfway, 10_000, now), 0.5);
+        assert_eq!(slow_start_weight(&old, 10_000, now), 1.0);
+        assert_eq!(slow_start_weight(&unknown, 10_000, now), 1.0);
+        assert_eq!(slow_start_weight(&fresh, 0, now), 1.0);
+    }
+
+    #[test]
+    fn spreads_requests_across_instances() {
+        let instances: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|id| instance(id, None))
+            .collect();
+        let picks = first_picks(&instances, 0);
+        for id in &["a", "b", "c"] {
+            let share = picks[*id] as f64 / 4_000.0;
+            assert!(share > 0.25 && share < 0.42, "{} got {}", id, share);
+        }
+    }
+
+    #[test]
+    fn warming_instances_get_a_reduced_share() {
+        let now = current_time_millis();
+        let instances = vec![
+            instance("old", Some(now - 3_600_000)),
+            instance("warming", Some(now - 1_800_000)),
+            instance("new", Some(now + 60_000)),
+        ];
+        let picks = first_picks(&instances, 3_600_000);
+        // Weights of 1 and 0.5, so the warming instance comes first a third of the time
+        let share = picks.get("warming").cloned().unwrap_or(0) as f64 / 4_000.0;
+        assert!(share > 0.25 && share < 0.42, "warming got {}", share);
+        assert_eq!(picks.get("new"), None);
+    }
+
+    #[test]
+    fn tiny_weights_keep_their_proportions() {
+        let now = current_time_millis();
+        let instances = vec![
+            instance("a", Some(now - 1_000)),
+            instance("b", Some(now - 2_000)),
+            instance("new", Some(now + 60_000)),
+        ];
+        // Weights of 0.00001 and 0.00002, where `random.powf(1.0 / weight)` underflows to 0
+        let picks = first_picks(&instances, 100_000_000);
+        let share = picks.get("a").cloned().unwrap_or(0) as f64 / 4_000.0;
+        assert!(share > 0.25 && share < 0.42, "a got {}", share);
+        assert_eq!(picks.get("new"), None);
+    }
+
+    #[test]
+    fn instances_without_weight_come_last() {
+        let now = current_time_millis();
+        let instances = vec![
+            instance("new", Some(now)),
+            instance("newer", Some(now + 60_000)),
+            instance("old", Some(now - 3_600_000)),
+        ];
+        for _ in 0..100 {
+            let ordered = load_balance(instances.clone(), 60_000);
+            assert_eq!(ordered[0].id(), "old");
+            assert_eq!(ordered.len(), 3);
+        }
+    }
+
+    #[test]
+    fn reports_changes_between_fetches() {
+        let mut updated = instance("b", Some(1));
+        updated.last_updated_timestamp = Some(5);
+        let old = group_instances_by_app(vec![
+            instance("a", Some(1)),
+            instance("b", Some(1)),
+            instance("c", Some(1)),
+        ]);
+        let new = group_instances_by_app(vec![
+            instance("a", Some(2)),
+            updated,
+            instance("d", Some(1)),
+        ]);
+        let mut events: Vec<_> = registry_events(&old, &new)
+            .into_iter()
+            .map(|event| match event {
+                RegistryEvent::Registered(i) => format!("registered {}", i.id()),
+                RegistryEvent::Restarted(i) => format!("restarted {}", i.id()),
+                RegistryEvent::Updated(i) => format!("updated {}", i.id()),
+                RegistryEvent::Removed(i) => format!("removed {}", i.id()),
+            })
+            .collect();
+        events.sort();
+        assert_eq!(
+            events,
+            vec!["registered d", "removed c", "restarted a", "updated b"]
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::multipart::Form;
+use reqwest::{Body, Client as ReqwestClient, Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use serde_urlencoded;
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Error as FmtError, Formatter};
+use std::io::Read;
+use std::iter;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use instance::instance_base_url;
+use registry::load_balance;
+use {excerpt, EurekaClient, EurekaError, TimeoutConfig};
+
+enum RequestBody {
+    Empty,
+    /// Bodies that can be sent again when a request is retried
+    Bytes(Vec<u8>),
+    /// Bodies that can only be sent once, and so are never retried
+    Multipart(Form),
+    Stream(Body),
+}
+
+/// A request to another app in the eureka cluster, created by `EurekaClient::request`.
+///
+/// The instance that receives the request is resolved through the registry when it is sent,
+/// and failed requests are retried according to `EurekaConfig::request_retry`.
+/// Multipart and streaming bodies can only be sent once, so those requests are never retried.
+pub struct AppRequest<'a> {
+    eureka: &'a EurekaClient,
+    app: String,
+    method: Method,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    body: RequestBody,
+    timeout: Option<TimeoutConfig>,
+    /// The first error from building the request, reported when it is sent
+    error: Option<EurekaError>,
+}
+
+impl<'a> AppRequest<'a> {
+    pub(crate) fn new(eureka: &'a EurekaClient, app: &str) -> Self {
+        AppRequest {
+            eureka,
+            app: app.to_string(),
+            method: Method::GET,
+            path: String::new(),
+            query: None,
+            headers: HeaderMap::new(),
+            body: RequestBody::Empty,
+            timeout: None,
+            error: None,
+        }
+    }
+
+    pub fn method(mut self, method: Method, path: &str) -> Self {
+        self.method = method;
+        self.path = path.to_string();
+        self
+    }
+
+    pub fn get(self, path: &str) -> Self {
+        self.method(Method::GET, path)
+    }
+
+    pub fn post(self, path: &str) -> Self {
+        self.method(Method::POST, path)
+    }
+
+    pub fn put(self, path: &str) -> Self {
+        self.method(Method::PUT, path)
+    }
+
+    pub fn patch(self, path: &str) -> Self {
+        self.method(Method::PATCH, path)
+    }
+
+    pub fn delete(self, path: &str) -> Self {
+        self.method(Method::DELETE, path)
+    }
+
+    pub fn head(self, path: &str) -> Self {
+        self.method(Method::HEAD, path)
+    }
+
+    /// Appends `query`, which must serialize to a sequence of key-value pairs, to the URL
+    pub fn query<T: Serialize + ?Sized>(mut self, query: &T) -> Self {
+        match serde_urlencoded::to_string(query) {
+            Ok(encoded) => {
+                self.query = Some(match self.query.take() {
+                    Some(existing) if !encoded.is_empty() => format!("{}&{}", existing, encoded),
+                    Some(existing) => existing,
+                    None => encoded,
+                });
+            }
+            Err(e) => self.fail(EurekaError::serialize(e)),
+        }
+        self
+    }
+
+    pub fn header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let name = HeaderName::from_bytes(key.as_ref().as_bytes());
+        let value = HeaderValue::from_str(value.as_ref());
+        match (name, value) {
+            (Ok(name), Ok(value)) => {
+                self.headers.insert(name, value);
+            }
+            _ => self.fail(EurekaError::InvalidConfig {
+                key: format!("header {}", key.as_ref()),
+                reason: "invalid header name or value".to_string(),
+            }),
+        }
+        self
+    }
+
+    /// Adds `headers`, replacing any already set with the same names.
+    /// Every value of a repeated header is kept.
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        for name in headers.keys() {
+            self.headers.remove(name);
+            for value in headers.get_all(name) {
+                self.headers.append(name.clone(), value.clone());
+            }
+        }
+        self
+    }
+
+    /// Overrides `EurekaConfig::timeouts.application` for this request.
+    /// Ignored with a warning when a custom client was passed to `EurekaClientBuilder::http_client`.
+    pub fn timeout(mut self, timeout: TimeoutConfig) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn body_bytes<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = RequestBody::Bytes(body.into());
+        self
+    }
+
+    /// Sends `body` as JSON, setting `Content-Type` unless it was already set
+    pub fn json<T: Serialize + ?Sized>(mut self, body: &T) -> Self {
+        match serde_json::to_vec(body) {
+            Ok(bytes) => {
+                self.default_content_type("application/json");
+                self.body = RequestBody::Bytes(bytes);
+            }
+            Err(e) => self.fail(EurekaError::serialize(e)),
+        }
+        self
+    }
+
+    /// Sends `body` URL-encoded, setting `Content-Type` unless it was already set
+    pub fn form<T: Serialize + ?Sized>(mut self, body: &T) -> Self {
+        match serde_urlencoded::to_string(body) {
+            Ok(encoded) => {
+                self.default_content_type("application/x-www-form-urlencoded");
+                self.body = RequestBody::Bytes(encoded.into_bytes());
+            }
+            Err(e) => self.fail(EurekaError::serialize(e)),
+        }
+        self
+    }
+
+    pub fn multipart(mut self, form: Form) -> Self {
+        self.body = RequestBody::Multipart(form);
+        self
+    }
+
+    /// Streams the body from `reader`, with chunked transfer encoding
+    pub fn stream<R: Read + Send + 'static>(mut self, reader: R) -> Self {
+        self.body = RequestBody::Stream(Body::new(reader));
+        self
+    }
+
+    fn default_content_type(&mut self, content_type: &'static str) {
+        if !self.headers.contains_key(CONTENT_TYPE) {
+            self.headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+    }
+
+    fn fail(&mut self, error: EurekaError) {
+        self.error.get_or_insert(error);
+    }
+
+    pub fn send(self) -> Result<Response, EurekaError> {
+        self.dispatch().map(|(resp, _)| resp)
+    }
+
+    /// Sends the request and deserializes a successful response from JSON as `T`.
+    ///
+    /// For an error status, the body is decoded as `E` when possible and returned in the error.
+    /// `Accept: "application/json"` is added unless another `Accept` header was set.
+    pub fn send_json<T, E>(mut self) -> Result<T, ResponseError<E>>
+    where
+        T: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        if !self.headers.contains_key(ACCEPT) {
+            self.headers
+                .insert(ACCEPT, HeaderValue::from_static("application/json"));
+        }
+        let app = self.app.clone();
+        let method = self.method.clone();
+        let (mut resp, instance) = self.dispatch().map_err(|cause| ResponseError {
+            app: app.clone(),
+            instance: None,
+            status: None,
+            body: None,
+            cause: Box::new(cause),
+        })?;
+        let status = resp.status();
+        let fail = |body, cause| ResponseError {
+            app: app.clone(),
+            instance: Some(instance.clone()),
+            status: Some(status),
+            body,
+            cause: Box::new(cause),
+        };
+        let text = resp.text().map_err(|e| fail(None, EurekaError::from(e)))?;
+        if status.is_success() {
+            // Allow empty bodies for types that accept `null`, such as `()` and `Option`
+            let json = if text.trim().is_empty() {
+                "null"
+            } else {
+                &text
+            };
+            return serde_json::from_str(json)
+                .map_err(|e| fail(None, EurekaError::deserialize(e, &text)));
+        }
+        let url = resp.url().to_string();
+        let body = excerpt(&text);
+        let cause = if status.is_server_error() {
+            EurekaError::ServerError {
+                operation: "make request",
+                method,
+                url,
+                status,
+                body,
+            }
+        } else {
+            EurekaError::Request {
+                operation: "make request",
+                method,
+                url,
+                status,
+                body,
+            }
+        };
+        Err(fail(serde_json::from_str(&text).ok(), cause))
+    }
+
+    /// Sends the request, returning the response and the ID of the instance that sent it
+    fn dispatch(mut self) -> Result<(Response, String), EurekaError> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        let eureka = self.eureka;
+        let client = eureka.client_for_timeout(self.timeout)?;
+        let app = self.app.clone();
+        let app = app.as_str();
+        let instances = if eureka.registry_enabled.load(Ordering::SeqCst) {
+            eureka.registry.get_instances_by_app_name(app)?
+        } else {
+            let instances = eureka.registry.get_static_instances(app);
+            if instances.is_empty() {
+                return Err(EurekaError::RegistryDisabled);
+            }
+            instances
+        };
+        let instances = load_balance(instances, eureka.config.eureka.slow_start_window);
+        if instances.is_empty() {
+            return Err(EurekaError::NoInstancesAvailable {
+                app: app.to_string(),
+            });
+        }
+        let target = eureka.config.eureka.request_target;
+        let mut targets = Vec::with_capacity(instances.len());
+        let mut unusable = None;
+        for instance in &instances {
+            match instance_base_url(instance, target) {
+                Ok(base_url) => targets.push((instance, base_url)),
+                Err(e) => {
+                    warn!("Skipping instance {} of {}: {}", instance.id(), app, e);
+                    unusable.get_or_insert(e);
+                }
+            }
+        }
+        if targets.is_empty() {
+            return Err(unusable.expect("instances without a base URL are always reported"));
+        }
+
+        let policy = &eureka.config.eureka.request_retry;
+        let can_resend = match self.body {
+            RequestBody::Empty | RequestBody::Bytes(_) => true,
+            RequestBody::Multipart(_) | RequestBody::Stream(_) => false,
+        };
+        let max_attempts = if can_resend && policy.is_retryable_method(&self.method) {
+            (policy.max_retries_next_instances + 1) * (policy.max_retries_same_instance + 1)
+        } else {
+            1
+        };
+        let attempts: Vec<_> = targets
+            .iter()
+            .filter(|&&(instance, _)| eureka.breakers.is_available(instance.id()))
+            .take(policy.max_retries_next_instances + 1)
+            .flat_map(|target| iter::repeat_n(target, policy.max_retries_same_instance + 1))
+            .take(max_attempts)
+            .collect();
+
+        let mut attempts_made = 0;
+        let mut last_error = None;
+        for (attempt, &&(instance, ref base_url)) in attempts.iter().enumerate() {
+            // Breakers are only acquired once we get to their instance,
+            // so that a half-open breaker isn't probed unless its instance is actually used
+            if !eureka.breakers.try_acquire(instance.id()) {
+                continue;
+            }
+            if attempts_made > 0 {
+                thread::sleep(policy.backoff(attempts_made));
+            }
+            attempts_made += 1;
+            let is_last_attempt = attempt + 1 == attempts.len();
+            let resp = self.send_to(&client, base_url);
+            match resp {
+                Ok(ref resp) if resp.status().is_server_error() => {
+                    eureka.breakers.record_failure(instance.id())
+                }
+                Ok(_) => eureka.breakers.record_success(instance.id()),
+                Err(_) => eureka.breakers.record_failure(instance.id()),
+            }
+            match resp {
+                Ok(resp) if !is_last_attempt && policy.is_retryable_status(resp.status()) => {
+                    warn!(
+                        "Request to instance {} of {} failed with {}, retrying",
+                        instance.id(),
+                        app,
+                        resp.status()
+                    );
+                    last_error = Some(EurekaError::from_status(
+                        "make request",
+                        self.method.clone(),
+                        Some(app),
+                        Some(instance.id()),
+                        resp,
+                    ));
+                }
+                Ok(resp) => return Ok((resp, instance.id().to_string())),
+                Err(e) => {
+                    warn!(
+                        "Request to instance {} of {} failed: {}",
+                        instance.id(),
+                        app,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            // Every instance of the app has been ejected by its circuit breaker
+            None => Err(EurekaError::NoInstancesAvailable {
+                app: app.to_string(),
+            }),
+            Some(last) if attempts_made > 1 => Err(EurekaError::AllServersFailed {
+                attempts: attempts_made,
+                last: Box::new(last),
+            }),
+            Some(last) => Err(last),
+        }
+    }
+
+    fn send_to(&mut self, client: &ReqwestClient, base_url: &str) -> Result<Response, EurekaError> {
+        let mut url = format!("{}/{}", base_url, self.path.trim_start_matches('/'));
+        if let Some(ref query) = self.query {
+            url.push(if url.contains('?') { '&' } else { '?' });
+            url.push_str(query);
+        }
+        let request = client
+            .request(self.method.clone(), &url)
+            .headers(self.headers.clone());
+        // One-shot bodies are taken by the first attempt, there are no further attempts for them
+        let request = match ::std::mem::replace(&mut self.body, RequestBody::Empty) {
+            RequestBody::Empty => request,
+            RequestBody::Bytes(bytes) => {
+                let request = request.body(bytes.clone());
+                self.body = RequestBody::Bytes(bytes);
+                request
+            }
+            RequestBody::Multipart(form) => request.multipart(form),
+            RequestBody::Stream(body) => request.body(body),
+        };
+        request.send().map_err(EurekaError::from)
+    }
+}
+
+/// A failed request to another app, from `AppRequest::send_json` or `EurekaClient::make_request_json`
+#[derive(Debug)]
+pub struct ResponseError<E> {
+    pub app: String,
+    /// The instance that responded, if any did
+    pub instance: Option<String>,
+    /// The response status, if there was a response
+    pub status: Option<StatusCode>,
+    /// The error response body decoded as `E`, if it could be decoded
+    pub body: Option<E>,
+    pub cause: Box<EurekaError>,
+}
+
+impl<E> Display for ResponseError<E> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        write!(f, "Request to app {}", self.app)?;
+        if let Some(ref instance) = self.instance {
+            write!(f, " (instance {})", instance)?;
+        }
+        write!(f, " failed: {}", self.cause)
+    }
+}
+
+impl<E: Debug> StdError for ResponseError<E> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.cause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use serde_json::Value;
+    use test_server::TestServer;
+    use {BaseConfig, EurekaClient, StatusCode};
+
+    /// Answers every request with 503 and the given body
+    fn unavailable_server(body: &'static str) -> String {
+        TestServer::new(move |_| (503, body.to_string())).address
+    }
+
+    fn client(addresses: Vec<String>) -> EurekaClient {
+        let mut config = BaseConfig::default();
+        config.eureka.register_with_eureka = false;
+        config.eureka.fetch_registry = false;
+        config.eureka.request_retry.initial_backoff = 0;
+        config
+            .eureka
+            .static_instances
+            .apps
+            .insert("APP".to_string(), addresses);
+        EurekaClient::new(config).unwrap()
+    }
+
+    #[test]
+    fn final_retryable_status_is_returned_as_response() {
+        for count in 1..=3 {
+            let addresses = (0..count).map(|_| unavailable_server("{}")).collect();
+            let eureka = client(addresses);
+            let resp = eureka.request("APP").get("/").send().unwrap();
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    #[test]
+    fn keeps_every_value_of_repeated_headers() {
+        let server = TestServer::new(|_| (200, String::new()));
+        let mut headers = HeaderMap::new();
+        headers.append("x-tag", HeaderValue::from_static("a"));
+        headers.append("x-tag", HeaderValue::from_static("b"));
+        client(vec![server.address.clone()])
+            .request("APP")
+            .header("x-tag", "replaced")
+            .header("x-other", "kept")
+            .headers(headers)
+            .get("/")
+            .send()
+            .unwrap();
+
+        let received = &server.received()[0];
+        let tags: Vec<_> = received
+            .headers
+            .iter()
+            .filter(|(name, _)| name == "x-tag")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(tags, vec!["a", "b"]);
+        assert_eq!(received.header("x-other"), Some("kept"));
+    }
+
+    #[test]
+    fn send_json_keeps_status_and_error_body() {
+        let eureka = client(vec![unavailable_server(r#"{"error":"down"}"#)]);
+        let error = eureka
+            .request("APP")
+            .get("/")
+            .send_json::<Value, Value>()
+            .unwrap_err();
+        assert_eq!(error.status, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(error.body.unwrap()["error"], "down");
+    }
+}
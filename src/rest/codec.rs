@@ -0,0 +1,222 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+use EurekaError;
+
+/// The format used for request and response bodies when talking to Eureka
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// Requires the `xml` feature
+    #[cfg(feature = "xml")]
+    Xml,
+}
+
+impl WireFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            WireFormat::Json => "application/json",
+            #[cfg(feature = "xml")]
+            WireFormat::Xml => "application/xml",
+        }
+    }
+
+    /// Picks the format matching a response's `Content-Type`, if it is one we understand
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        if mime.ends_with("/json") || mime.ends_with("+json") {
+            return Some(WireFormat::Json);
+        }
+        #[cfg(feature = "xml")]
+        {
+            if mime.ends_with("/xml") || mime.ends_with("+xml") {
+                return Some(WireFormat::Xml);
+            }
+        }
+        None
+    }
+
+    /// Serializes `value` wrapped in a `root` element,
+    /// e.g. `{"instance": {...}}` in JSON or `<instance>...</instance>` in XML
+    pub fn encode<T: Serialize>(self, root: &str, value: &T) -> Result<Vec<u8>, EurekaError> {
+        let value = serde_json::to_value(value).map_err(EurekaError::serialize)?;
+        match self {
+            WireFormat::Json => {
+                let mut wrapper = serde_json::Map::with_capacity(1);
+                wrapper.insert(root.to_string(), value);
+                serde_json::to_vec(&wrapper).map_err(EurekaError::serialize)
+            }
+            #[cfg(feature = "xml")]
+            WireFormat::Xml => {
+                let mut out = String::new();
+                xml::write_element(&mut out, root, &value);
+                Ok(out.into_bytes())
+            }
+        }
+    }
+
+    /// Deserializes a body whose content is wrapped in a `root` element
+    pub fn decode<T: DeserializeOwned>(self, root: &str, body: &str) -> Result<T, EurekaError> {
+        match self {
+            WireFormat::Json => {
+                let mut wrapper: Value =
+                    serde_json::from_str(body).map_err(|e| EurekaError::deserialize(e, body))?;
+                let value = wrapper.get_mut(root).map(Value::take).ok_or_else(|| {
+                    EurekaError::deserialize(format!("missing `{}` field", root), body)
+                })?;
+                serde_json::from_value(value).map_err(|e| EurekaError::deserialize(e, body))
+            }
+            // The root element name is not checked by the XML deserializer
+            #[cfg(feature = "xml")]
+            WireFormat::Xml => {
+                ::quick_xml::de::from_str(body).map_err(|e| EurekaError::deserialize(e, body))
+            }
+        }
+    }
+}
+
+/// Writes JSON values as XML using the same conventions as Eureka's XStream converter:
+/// keys starting with `@` become attributes, `$` becomes the element's text,
+/// and arrays become repeated elements.
+#[cfg(feature = "xml")]
+mod xml {
+    use quick_xml::escape::escape;
+    use serde_json::Value;
+
+    pub fn write_element(out: &mut String, name: &str, value: &Value) {
+        match *value {
+            Value::Null => {}
+            Value::Array(ref items) => {
+                for item in items {
+                    write_element(out, name, item);
+                }
+            }
+            Value::Object(ref map) => {
+                out.push('<');
+                out.push_str(name);
+                for (key, value) in map.iter().filter(|&(k, _)| k.starts_with('@')) {
+                    out.push(' ');
+                    out.push_str(&key[1..]);
+                    out.push_str("=\"");
+                    out.push_str(&escape(text(value)));
+                    out.push('"');
+                }
+                out.push('>');
+                for (key, value) in map.iter().filter(|&(k, _)| !k.starts_with('@')) {
+                    if key == "$" {
+                        out.push_str(&escape(text(value)));
+                    } else {
+                        write_element(out, key, value);
+                    }
+                }
+                out.push_str("</");
+                out.push_str(name);
+                out.push('>');
+            }
+            _ => {
+                out.push('<');
+                out.push_str(name);
+                out.push('>');
+                out.push_str(&escape(text(value)));
+                out.push_str("</");
+                out.push_str(name);
+                out.push('>');
+            }
+        }
+    }
+
+    fn text(value: &Value) -> String {
+        match *value {
+            Value::String(ref s) => s.clone(),
+            Value::Null => String::new(),
+            ref other => other.to_string(),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "xml"))]
+mod tests {
+    use super::*;
+    use rest::structures::{Applications, Instance, StatusType};
+
+    const INSTANCE: &str = include_str!("../../tests/fixtures/instance.xml");
+    const APPLICATIONS: &str = include_str!("../../tests/fixtures/applications.xml");
+
+    #[test]
+    fn decodes_instance() {
+        let instance: Instance = WireFormat::Xml.decode("instance", INSTANCE).unwrap();
+        assert_eq!(instance.id(), "10.0.0.11:ORDER-SERVICE:8080");
+        assert_eq!(instance.status, StatusType::Up);
+        assert_eq!(
+            instance.port.as_ref().and_then(|port| port.value()),
+            Some(8080)
+        );
+        assert_eq!(instance.secure_port.value(), None);
+        let metadata = instance.metadata.as_ref().unwrap();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata["management.port"], "8081");
+        assert_eq!(metadata["zone"], "eu-west-1a");
+        let lease = instance.lease_info.unwrap();
+        assert_eq!(lease.renewal_interval_in_secs, Some(30));
+        assert_eq!(lease.registration_timestamp, Some(1_700_000_000_000));
+        assert_eq!(instance.last_updated_timestamp, Some(1_700_000_000_001));
+        assert_eq!(instance.last_dirty_timestamp, Some(1_699_999_999_000));
+    }
+
+    #[test]
+    fn decodes_applications() {
+        let apps: Applications = WireFormat::Xml
+            .decode("applications", APPLICATIONS)
+            .unwrap();
+        assert_eq!(apps.apps_hashcode.as_ref().unwrap(), "UP_2_");
+        assert_eq!(apps.application.len(), 2);
+        let order = &apps.application[0].instance[0];
+        assert_eq!(order.metadata.as_ref().map(|m| m.len()), Some(0));
+        let user = &apps.application[1].instance[0];
+        assert_eq!(user.port.as_ref().and_then(|port| port.value()), None);
+        assert_eq!(user.secure_port.value(), Some(9443));
+        assert_eq!(user.metadata.as_ref().unwrap()["version"], "2.3.1");
+        assert_eq!(
+            user.lease_info.unwrap().registration_timestamp,
+            Some(1_700_000_100_000)
+        );
+    }
+
+    #[test]
+    fn instance_round_trips() {
+        let instance: Instance = WireFormat::Xml.decode("instance", INSTANCE).unwrap();
+        let encoded = WireFormat::Xml.encode("instance", &instance).unwrap();
+        let encoded = String::from_utf8(encoded).unwrap();
+        assert!(encoded.starts_with("<instance>"));
+        assert!(encoded.contains("<port enabled=\"true\">8080</port>"));
+        assert!(encoded.contains(
+            "<dataCenterInfo class=\"com.netflix.appinfo.InstanceInfo$DefaultDataCenterInfo\">"
+        ));
+        let decoded: Instance = WireFormat::Xml.decode("instance", &encoded).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&instance).unwrap()
+        );
+    }
+
+    #[test]
+    fn applications_round_trip() {
+        let apps: Applications = WireFormat::Xml
+            .decode("applications", APPLICATIONS)
+            .unwrap();
+        for app in &apps.application {
+            for instance in &app.instance {
+                let encoded = WireFormat::Xml.encode("instance", instance).unwrap();
+                let decoded: Instance = WireFormat::Xml
+                    .decode("instance", &String::from_utf8(encoded).unwrap())
+                    .unwrap();
+                assert_eq!(
+                    serde_json::to_value(&decoded).unwrap(),
+                    serde_json::to_value(instance).unwrap()
+                );
+            }
+        }
+    }
+}
@@ -1,17 +1,20 @@
-use serde_json;
+use builder::InstanceBuilder;
+use serde::de::{Deserializer, Error as DeError};
+use serde::Deserialize;
+use serde_json::{self, Value};
 use std::collections::HashMap;
 use std::env;
 use std::fmt::{Display, Error as FmtError, Formatter};
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Register<'a> {
-    pub instance: &'a Instance,
-}
+use std::time::Duration;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Instance {
-    /// This doubles as the instance ID, because why not, Eureka?
+    /// Unique ID of this instance within its app.
+    /// Older Eureka servers don't send this, in which case `host_name` is used instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    /// The hostname advertised to other services
     pub host_name: String,
     pub app: String,
     pub ip_addr: String,
@@ -26,12 +29,33 @@ pub struct Instance {
     pub data_center_info: DataCenterInfo,
     pub lease_info: Option<LeaseInfo>,
     /// optional app specific metadata
+    #[serde(default, deserialize_with = "deserialize_metadata")]
     pub metadata: Option<HashMap<String, String>>,
+    /// Milliseconds since the epoch when this instance's data was last changed locally.
+    /// Eureka uses this to decide which copy of an instance is newer.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        deserialize_with = "deserialize_timestamp"
+    )]
+    pub last_dirty_timestamp: Option<u64>,
+    /// Milliseconds since the epoch when Eureka last changed this instance, as on a status change.
+    /// Only set on instances fetched from Eureka.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        deserialize_with = "deserialize_timestamp"
+    )]
+    pub last_updated_timestamp: Option<u64>,
+    /// Only set on instances returned by a delta query
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub action_type: Option<ActionType>,
 }
 
 impl Default for Instance {
     fn default() -> Self {
         Instance {
+            instance_id: None,
             host_name: "localhost".to_string(),
             app: env::var("CARGO_PKG_NAME").unwrap_or_default(),
             ip_addr: "127.0.0.1".to_string(),
@@ -46,13 +70,94 @@ impl Default for Instance {
             data_center_info: DataCenterInfo::default(),
             lease_info: None,
             metadata: None,
+            last_dirty_timestamp: None,
+            last_updated_timestamp: None,
+            action_type: None,
         }
     }
 }
 
+impl Instance {
+    /// Starts building an instance, deriving what it can from the app name and port
+    pub fn builder() -> InstanceBuilder {
+        InstanceBuilder::new()
+    }
+
+    /// The ID used to address this instance in per-instance REST calls
+    pub fn id(&self) -> &str {
+        self.instance_id.as_ref().unwrap_or(&self.host_name)
+    }
+}
+
+/// Eureka sends timestamps as strings in JSON, and in XML they are element text,
+/// which the XML deserializer hands over as `{"$text": "..."}`
+fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    let value = Option::<Value>::deserialize(deserializer)?.unwrap_or(Value::Null);
+    timestamp(value).map_err(D::Error::custom)
+}
+
+/// In XML the metadata element carries a `class` attribute,
+/// as in `<metadata class="java.util.Collections$EmptyMap"/>`, which isn't part of the map
+fn deserialize_metadata<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<HashMap<String, String>>, D::Error> {
+    let map = match Option::<HashMap<String, Value>>::deserialize(deserializer)? {
+        Some(map) => map,
+        None => return Ok(None),
+    };
+    map.into_iter()
+        .filter(|(key, _)| !key.starts_with('@'))
+        .map(|(key, value)| match metadata_text(value) {
+            Some(text) => Ok((key, text)),
+            None => Err(D::Error::custom(format!(
+                "invalid value for metadata {}",
+                key
+            ))),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn metadata_text(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Null => Some(String::new()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Object(mut map) => match map.remove("$text").or_else(|| map.remove("$value")) {
+            Some(text) => metadata_text(text),
+            None if map.is_empty() => Some(String::new()),
+            None => None,
+        },
+        Value::Array(_) => None,
+    }
+}
+
+fn timestamp(value: Value) -> Result<Option<u64>, String> {
+    match value {
+        Value::Null => Ok(None),
+        Value::Number(n) => n
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| "timestamp out of range".to_string()),
+        Value::String(ref s) if s.trim().is_empty() => Ok(None),
+        Value::String(s) => s.trim().parse::<u64>().map(Some).map_err(|e| e.to_string()),
+        Value::Object(mut map) => match map.remove("$text").or_else(|| map.remove("$value")) {
+            Some(text) => timestamp(text),
+            // An empty element
+            None if map.is_empty() => Ok(None),
+            None => Err(format!("invalid timestamp: {}", Value::Object(map))),
+        },
+        other => Err(format!("invalid timestamp: {}", other)),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PortData {
-    #[serde(rename = "$")]
+    // `$text` is how the XML deserializer names element text
+    #[serde(rename = "$", alias = "$text")]
     value: u16,
     #[serde(rename = "@enabled")]
     enabled: String,
@@ -75,32 +180,23 @@ impl PortData {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct AllApplications {
-    pub applications: Applications,
-}
-
 #[derive(Debug, Clone, Deserialize)]
 pub struct Applications {
+    #[serde(rename = "versions__delta", default)]
+    pub versions_delta: Option<String>,
+    #[serde(rename = "apps__hashcode", default)]
+    pub apps_hashcode: Option<String>,
+    #[serde(default)]
     pub application: Vec<Application>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct ApplicationWrapper {
-    pub application: Application,
-    pub name: String,
-}
-
 #[derive(Debug, Clone, Deserialize)]
 pub struct Application {
+    pub name: String,
+    #[serde(default)]
     pub instance: Vec<Instance>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct InstanceWrapper {
-    pub instance: Instance,
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataCenterInfo {
     #[serde(rename = "@class")]
@@ -111,6 +207,17 @@ pub struct DataCenterInfo {
     pub metadata: Option<AmazonMetadataType>,
 }
 
+impl DataCenterInfo {
+    /// Data center info for an instance running on AWS
+    pub fn amazon(metadata: AmazonMetadataType) -> Self {
+        DataCenterInfo {
+            class: "com.netflix.appinfo.AmazonInfo".into(),
+            name: DcNameType::Amazon,
+            metadata: Some(metadata),
+        }
+    }
+}
+
 impl Default for DataCenterInfo {
     fn default() -> Self {
         DataCenterInfo {
@@ -121,14 +228,47 @@ impl Default for DataCenterInfo {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LeaseInfo {
+    /// How often heartbeats are sent, in seconds.
+    /// Defaults to `EurekaConfig::heartbeat_interval`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub renewal_interval_in_secs: Option<usize>,
+    /// How long Eureka waits for a heartbeat before evicting the instance, in seconds.
+    /// Defaults to `eviction_duration_in_secs`, then to 90 seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_in_secs: Option<usize>,
     /// (optional) if you want to change the length of lease - default if 90 secs
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub eviction_duration_in_secs: Option<usize>,
+    /// Milliseconds since the epoch when the instance last registered.
+    /// Only set on instances fetched from Eureka, and changes when an instance restarts.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        default,
+        deserialize_with = "deserialize_timestamp"
+    )]
+    pub registration_timestamp: Option<u64>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl LeaseInfo {
+    /// The lease Eureka uses when an instance doesn't specify one
+    pub const DEFAULT_DURATION_IN_SECS: usize = 90;
+
+    /// The renewal interval, or zero if it's not set
+    pub fn renewal_interval(&self) -> Duration {
+        Duration::from_secs(self.renewal_interval_in_secs.unwrap_or(0) as u64)
+    }
+
+    pub fn duration(&self) -> usize {
+        self.duration_in_secs
+            .or(self.eviction_duration_in_secs)
+            .unwrap_or(Self::DEFAULT_DURATION_IN_SECS)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum DcNameType {
     MyOwn,
     Amazon,
@@ -160,7 +300,25 @@ impl Display for StatusType {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ActionType {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// A status change for `EurekaRestClient::update_statuses`
+#[derive(Debug, Clone)]
+pub struct StatusUpdate {
+    pub app: String,
+    pub instance_id: String,
+    pub status: StatusType,
+    /// Lets the server reject the update if its copy of the instance is newer
+    pub last_dirty_timestamp: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct AmazonMetadataType {
     pub ami_launch_index: String,
@@ -175,3 +333,58 @@ pub struct AmazonMetadataType {
     pub ami_id: String,
     pub instance_type: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rest::codec::WireFormat;
+
+    #[test]
+    fn json_timestamps_may_be_strings_or_numbers() {
+        let instance: Instance = WireFormat::Json
+            .decode(
+                "instance",
+                r#"{"instance": {"hostName": "a", "app": "A", "ipAddr": "10.0.0.1",
+                "vipAddress": "a", "secureVipAddress": "a", "status": "UP",
+                "port": {"$": 8080, "@enabled": "true"},
+                "securePort": {"$": 443, "@enabled": "false"},
+                "homePageUrl": "", "statusPageUrl": "", "healthCheckUrl": "",
+                "dataCenterInfo": {"@class": "x", "name": "MyOwn"},
+                "leaseInfo": {"registrationTimestamp": 1700000000000},
+                "lastUpdatedTimestamp": "1700000000001", "lastDirtyTimestamp": 1700000000002}}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            instance.lease_info.unwrap().registration_timestamp,
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(instance.last_updated_timestamp, Some(1_700_000_000_001));
+        assert_eq!(instance.last_dirty_timestamp, Some(1_700_000_000_002));
+    }
+
+    #[cfg(feature = "xml")]
+    #[test]
+    fn xml_timestamps_are_element_text() {
+        let instance: Instance = WireFormat::Xml
+            .decode(
+                "instance",
+                "<instance><hostName>a</hostName><app>A</app><ipAddr>10.0.0.1</ipAddr>\
+                 <vipAddress>a</vipAddress><secureVipAddress>a</secureVipAddress>\
+                 <status>UP</status><port enabled=\"true\">8080</port>\
+                 <securePort enabled=\"false\">443</securePort>\
+                 <homePageUrl></homePageUrl><statusPageUrl></statusPageUrl>\
+                 <healthCheckUrl></healthCheckUrl>\
+                 <dataCenterInfo class=\"x\"><name>MyOwn</name></dataCenterInfo>\
+                 <leaseInfo><registrationTimestamp>1700000000000</registrationTimestamp></leaseInfo>\
+                 <lastUpdatedTimestamp>1700000000001</lastUpdatedTimestamp>\
+                 <lastDirtyTimestamp>1700000000002</lastDirtyTimestamp></instance>",
+            )
+            .unwrap();
+        assert_eq!(
+            instance.lease_info.unwrap().registration_timestamp,
+            Some(1_700_000_000_000)
+        );
+        assert_eq!(instance.last_updated_timestamp, Some(1_700_000_000_001));
+        assert_eq!(instance.last_dirty_timestamp, Some(1_700_000_000_002));
+    }
+}
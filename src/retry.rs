@@ -0,0 +1,120 @@
+use reqwest::{Method, StatusCode};
+use std::time::Duration;
+
+use random_u64;
+
+/// Controls how `EurekaClient::make_request` retries failed requests,
+/// both against the same instance and against other instances of the app
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RetryPolicy {
+    /// Retries against the instance that just failed, before moving on to another
+    pub max_retries_same_instance: usize,
+    /// How many other instances to try once retries on an instance are exhausted
+    pub max_retries_next_instances: usize,
+    /// Delay before the first retry, in milliseconds, doubled on every further retry
+    pub initial_backoff: usize,
+    /// Upper bound for the retry delay, in milliseconds
+    pub max_backoff: usize,
+    /// Fraction of each delay, between 0 and 1, that is randomized
+    /// so that clients which failed together don't retry together
+    pub jitter: f64,
+    /// Response statuses that are treated as failures and retried
+    pub retryable_statuses: Vec<u16>,
+    /// Methods that are safe to retry. Defaults to the idempotent methods.
+    pub retryable_methods: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries_same_instance: 0,
+            max_retries_next_instances: 1,
+            initial_backoff: 100,
+            max_backoff: 2_000,
+            jitter: 0.5,
+            retryable_statuses: vec![502, 503, 504],
+            retryable_methods: ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which sends every request exactly once
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_retries_same_instance: 0,
+            max_retries_next_instances: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    pub fn is_retryable_method(&self, method: &Method) -> bool {
+        self.retryable_methods
+            .iter()
+            .any(|retryable| retryable.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    pub fn is_retryable_status(&self, status: StatusCode) -> bool {
+        self.retryable_statuses.contains(&status.as_u16())
+    }
+
+    /// The delay before retry number `retry`, counting from 1
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let delay = (self.initial_backoff as u64)
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff as u64);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let random = random_u64() as f64 / u64::MAX as f64;
+        let delay = delay as f64 * (1.0 - jitter * random);
+        Duration::from_millis(delay as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_backoff: 100,
+            max_backoff: 1_000,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (1..=6).map(|retry| policy.backoff(retry)).collect();
+        let expected: Vec<_> = [100, 200, 400, 800, 1_000, 1_000]
+            .iter()
+            .map(|&millis| Duration::from_millis(millis))
+            .collect();
+        assert_eq!(delays, expected);
+        assert_eq!(policy.backoff(1_000), Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy {
+            initial_backoff: 400,
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.backoff(1);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+    }
+
+    #[test]
+    fn retries_idempotent_methods_and_gateway_errors() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable_method(&Method::GET));
+        assert!(!policy.is_retryable_method(&Method::POST));
+        assert!(policy.is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!policy.is_retryable_status(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
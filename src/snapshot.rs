@@ -0,0 +1,167 @@
+use serde_json;
+use std::fs;
+
+use current_time_millis;
+use rest::structures::Instance;
+
+/// Keeps a copy of the registry on disk, so that apps can still be found
+/// if Eureka is unreachable when the service starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RegistryCacheConfig {
+    /// File the registry is saved to after every successful fetch. Disabled when not set.
+    pub path: Option<String>,
+    /// Snapshots older than this are ignored at startup, in milliseconds. Zero means no limit.
+    pub max_staleness: usize,
+}
+
+impl Default for RegistryCacheConfig {
+    fn default() -> Self {
+        RegistryCacheConfig {
+            path: None,
+            max_staleness: 86_400_000,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    /// Milliseconds since the epoch when the registry was fetched
+    timestamp: u64,
+    instances: &'a [Instance],
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    timestamp: u64,
+    instances: Vec<Instance>,
+}
+
+/// Reads the saved registry, unless there is none or it is too old
+pub fn load(config: &RegistryCacheConfig) -> Option<Vec<Instance>> {
+    let path = config.path.as_ref()?;
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            debug!("No registry snapshot loaded from {}: {}", path, e);
+            return None;
+        }
+    };
+    let snapshot: Snapshot = match serde_json::from_str(&contents) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!("Ignoring unreadable registry snapshot {}: {}", path, e);
+            return None;
+        }
+    };
+    let age = current_time_millis().saturating_sub(snapshot.timestamp);
+    if config.max_staleness > 0 && age > config.max_staleness as u64 {
+        warn!(
+            "Ignoring registry snapshot {}, which is {} seconds old",
+            path,
+            age / 1000
+        );
+        return None;
+    }
+    info!(
+        "Loaded registry snapshot {} from {} seconds ago",
+        path,
+        age / 1000
+    );
+    Some(snapshot.instances)
+}
+
+/// Saves the registry, replacing the previous snapshot atomically
+pub fn save(config: &RegistryCacheConfig, instances: &[Instance]) {
+    let path = match config.path {
+        Some(ref path) => path,
+        None => return,
+    };
+    let snapshot = SnapshotRef {
+        timestamp: current_time_millis(),
+        instances,
+    };
+    let temp_path = format!("{}.tmp", path);
+    let result = serde_json::to_vec(&snapshot)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| fs::write(&temp_path, contents).map_err(|e| e.to_string()))
+        .and_then(|()| fs::rename(&temp_path, path).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("Failed to save registry snapshot {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::Path;
+
+    fn cache(name: &str, max_staleness: usize) -> RegistryCacheConfig {
+        let path = env::temp_dir().join(format!("eureka-snapshot-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        RegistryCacheConfig {
+            path: Some(path.to_string_lossy().into_owned()),
+            max_staleness,
+        }
+    }
+
+    fn instance(id: &str) -> Instance {
+        Instance {
+            instance_id: Some(id.to_string()),
+            app: "APP".to_string(),
+            ..Instance::default()
+        }
+    }
+
+    /// Writes a snapshot taken `age` milliseconds ago
+    fn write_snapshot(config: &RegistryCacheConfig, age: u64) {
+        let snapshot = SnapshotRef {
+            timestamp: current_time_millis() - age,
+            instances: &[instance("a")],
+        };
+        let path = config.path.as_ref().unwrap();
+        fs::write(path, serde_json::to_vec(&snapshot).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn loads_saved_registry() {
+        let config = cache("round-trip", 60_000);
+        save(&config, &[instance("a"), instance("b")]);
+
+        let loaded = load(&config).unwrap();
+        let ids: Vec<_> = loaded.iter().map(Instance::id).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(loaded[0].app, "APP");
+        let temp_path = format!("{}.tmp", config.path.unwrap());
+        assert!(!Path::new(&temp_path).exists());
+    }
+
+    #[test]
+    fn ignores_snapshots_older_than_max_staleness() {
+        let config = cache("stale", 60_000);
+        write_snapshot(&config, 120_000);
+        assert!(load(&config).is_none());
+
+        write_snapshot(&config, 30_000);
+        assert!(load(&config).is_some());
+    }
+
+    #[test]
+    fn zero_max_staleness_accepts_any_age() {
+        let config = cache("no-limit", 0);
+        write_snapshot(&config, 30 * 86_400_000);
+        assert!(load(&config).is_some());
+    }
+
+    #[test]
+    fn ignores_missing_and_unreadable_snapshots() {
+        let config = cache("unreadable", 60_000);
+        assert!(load(&config).is_none());
+
+        fs::write(config.path.as_ref().unwrap(), "not a snapshot").unwrap();
+        assert!(load(&config).is_none());
+
+        assert!(load(&RegistryCacheConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,166 @@
+extern crate eureka_client;
+extern crate openssl;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+use eureka_client::{BaseConfig, EurekaClient, RetryPolicy, StatusCode, TlsConfig};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+
+struct Cert {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+fn certificate(name: &str, issuer: Option<&Cert>) -> Cert {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", name).unwrap();
+    let subject = subject.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    match issuer {
+        None => {
+            builder.set_issuer_name(&subject).unwrap();
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+            builder.sign(&key, MessageDigest::sha256()).unwrap();
+        }
+        Some(issuer) => {
+            builder.set_issuer_name(issuer.cert.subject_name()).unwrap();
+            let san = SubjectAlternativeName::new()
+                .dns("localhost")
+                .ip("127.0.0.1")
+                .build(&builder.x509v3_context(Some(&issuer.cert), None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+            builder.sign(&issuer.key, MessageDigest::sha256()).unwrap();
+        }
+    }
+    Cert {
+        cert: builder.build(),
+        key,
+    }
+}
+
+fn temp_file(name: &str, contents: &[u8]) -> String {
+    let path = env::temp_dir().join(format!("eureka-tls-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// Serves `200 OK` over TLS, requiring a client certificate signed by `ca` if `mutual` is set
+fn tls_server(ca: &Cert, server: Cert, mutual: bool) -> u16 {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&server.key).unwrap();
+    acceptor.set_certificate(&server.cert).unwrap();
+    if mutual {
+        acceptor.cert_store_mut().add_cert(ca.cert.clone()).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream.map(|stream| acceptor.accept(stream)) {
+                Ok(Ok(stream)) => stream,
+                _ => continue,
+            };
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf);
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+        }
+    });
+    port
+}
+
+fn client(port: u16, tls: TlsConfig) -> EurekaClient {
+    let mut config = BaseConfig::default();
+    config.eureka.register_with_eureka = false;
+    config.eureka.fetch_registry = false;
+    config.eureka.request_retry = RetryPolicy::none();
+    config.eureka.tls = tls;
+    config.eureka.static_instances.apps.insert(
+        "SECURE".to_string(),
+        vec![format!("https://localhost:{}", port)],
+    );
+    EurekaClient::new(config).unwrap()
+}
+
+#[test]
+fn trusts_ca_from_ca_certs() {
+    let ca = certificate("Test CA", None);
+    let server = certificate("localhost", Some(&ca));
+    let port = tls_server(&ca, server, false);
+
+    let untrusted = client(port, TlsConfig::default());
+    assert!(untrusted.request("SECURE").get("/").send().is_err());
+
+    let tls = TlsConfig {
+        ca_certs: vec![temp_file("ca.pem", &ca.cert.to_pem().unwrap())],
+        ..TlsConfig::default()
+    };
+    let resp = client(port, tls).request("SECURE").get("/").send().unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[test]
+fn sends_client_identity_for_mutual_tls() {
+    let ca = certificate("Test CA", None);
+    let server = certificate("localhost", Some(&ca));
+    let identity = certificate("client", Some(&ca));
+    let port = tls_server(&ca, server, true);
+    let ca_certs = vec![temp_file("mutual-ca.pem", &ca.cert.to_pem().unwrap())];
+
+    let anonymous = client(
+        port,
+        TlsConfig {
+            ca_certs: ca_certs.clone(),
+            ..TlsConfig::default()
+        },
+    );
+    assert!(anonymous.request("SECURE").get("/").send().is_err());
+
+    let pkcs12 = Pkcs12::builder()
+        .name("client")
+        .pkey(&identity.key)
+        .cert(&identity.cert)
+        .build2("secret")
+        .unwrap();
+    let tls = TlsConfig {
+        ca_certs,
+        client_identity: Some(temp_file("client.p12", &pkcs12.to_der().unwrap())),
+        client_identity_password: "secret".to_string(),
+        ..TlsConfig::default()
+    };
+    let resp = client(port, tls).request("SECURE").get("/").send().unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}